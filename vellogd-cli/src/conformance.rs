@@ -0,0 +1,223 @@
+// A visual conformance test of the line ends and the line joins. Each
+// combination is drawn on its own page, exported as PNG, and compared with the
+// reference image. Run the server with --headless so that the result doesn't
+// depend on the window.
+//
+// The reference images are in vellogd-cli/conformance-reference. Since the
+// antialiasing differs among GPUs, they are generated with the software
+// adapter (llvmpipe on Linux), which gives the same result on any machine:
+//
+//     vellogd-server --headless --adapter software
+//     vellogd-cli conformance --update
+//
+// Check the 9 images by eye before committing them. Without --update, the
+// command fails if any image differs more than --max-diff-ratio.
+
+use std::{error::Error, path::Path};
+
+use tonic::transport::Channel;
+use vellogd_protocol::graphics_device_client::GraphicsDeviceClient;
+use vellogd_protocol::*;
+
+// cf. https://github.com/r-devel/r-svn/blob/6ad1e0f2702fd0308e4f3caac2e22541d014ab6a/src/include/R_ext/GraphicsEngine.h#L183-L187
+const CAPS: [(u32, &str); 3] = [(1, "round"), (2, "butt"), (3, "square")];
+const JOINS: [(u32, &str); 3] = [(1, "round"), (2, "mitre"), (3, "bevel")];
+
+const PAGE_SIZE: u32 = 120;
+
+// The antialiasing differs slightly among GPUs, so a pixel counts as different
+// only if any channel differs more than this.
+const CHANNEL_TOLERANCE: u8 = 16;
+
+pub(crate) struct Options<'a> {
+    pub(crate) output: &'a Path,
+    pub(crate) reference: &'a Path,
+    // If true, overwrite the reference images with the results
+    pub(crate) update: bool,
+    // The maximum ratio of the different pixels to pass
+    pub(crate) max_diff_ratio: f64,
+}
+
+pub(crate) async fn run(
+    client: &mut GraphicsDeviceClient<Channel>,
+    device_id: u32,
+    options: Options<'_>,
+) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(options.output)?;
+    if options.update {
+        std::fs::create_dir_all(options.reference)?;
+    } else if !options.reference.is_dir() {
+        return Err(format!(
+            "no reference images in {}. Generate them with --update",
+            options.reference.display()
+        )
+        .into());
+    }
+
+    let mut failures = Vec::new();
+    for (cap, cap_name) in CAPS {
+        for (join, join_name) in JOINS {
+            let name = format!("cap-{cap_name}-join-{join_name}.png");
+            let data = draw_and_export(client, device_id, cap, join).await?;
+            std::fs::write(options.output.join(&name), &data)?;
+
+            let reference_path = options.reference.join(&name);
+            if options.update {
+                std::fs::write(&reference_path, &data)?;
+                println!("{name}: updated the reference");
+                continue;
+            }
+
+            if !reference_path.exists() {
+                println!("{name}: FAILED (no reference image)");
+                failures.push(name);
+                continue;
+            }
+
+            let ratio = diff_ratio(&data, &std::fs::read(&reference_path)?)?;
+            if ratio > options.max_diff_ratio {
+                println!(
+                    "{name}: FAILED ({:.2}% of the pixels differ)",
+                    ratio * 100.0
+                );
+                failures.push(name);
+            } else {
+                println!("{name}: ok");
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        return Err(format!(
+            "{} of {} combinations differ from the references: {failures:?}",
+            failures.len(),
+            CAPS.len() * JOINS.len()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+async fn draw_and_export(
+    client: &mut GraphicsDeviceClient<Channel>,
+    device_id: u32,
+    cap: u32,
+    join: u32,
+) -> Result<Vec<u8>, tonic::Status> {
+    client
+        .new_page(NewPageRequest {
+            device_id,
+            background_color: Some(pack_rgba([255, 255, 255, 255])),
+            width: PAGE_SIZE,
+            height: PAGE_SIZE,
+            background_color_rgba: None,
+        })
+        .await?;
+
+    // A zigzag with a sharp angle shows both the ends and the join
+    let x = vec![25.0, 60.0, 95.0];
+    let y = vec![90.0, 30.0, 90.0];
+
+    client
+        .draw_polyline(DrawPolylineRequest {
+            x: x.clone(),
+            y: y.clone(),
+            stroke_params: Some(StrokeParameters {
+                color: pack_rgba([0, 0, 0, 255]),
+                width: 16.0,
+                linetype: 0, // LTY_SOLID
+                join,
+                miter_limit: 10.0,
+                cap,
+                width_unit: LineWidthUnit::Pixel.into(),
+                color_rgba: None,
+            }),
+            device_id,
+        })
+        .await?;
+
+    // The center line to see how far the ends and the join extend
+    client
+        .draw_polyline(DrawPolylineRequest {
+            x,
+            y,
+            stroke_params: Some(StrokeParameters {
+                color: pack_rgba([255, 0, 0, 255]),
+                width: 1.0,
+                linetype: 0, // LTY_SOLID
+                join: 1,
+                miter_limit: 10.0,
+                cap: 2,
+                width_unit: LineWidthUnit::Pixel.into(),
+                color_rgba: None,
+            }),
+            device_id,
+        })
+        .await?;
+
+    let response = client
+        .export_png(ExportPngRequest {
+            path: None,
+            width: 0,
+            height: 0,
+            scale: 1.0,
+            transparent: false,
+            device_id,
+        })
+        .await?;
+
+    Ok(response.into_inner().data)
+}
+
+/// Returns the ratio of the pixels that differ between the two PNG images.
+/// If the sizes differ, all the pixels are considered different.
+fn diff_ratio(actual: &[u8], expected: &[u8]) -> Result<f64, png::DecodingError> {
+    let actual = decode_png(actual)?;
+    let expected = decode_png(expected)?;
+    if (actual.0, actual.1) != (expected.0, expected.1) {
+        return Ok(1.0);
+    }
+
+    let num_pixels = (actual.0 * actual.1) as usize;
+    let num_diffs = actual
+        .2
+        .chunks_exact(4)
+        .zip(expected.2.chunks_exact(4))
+        .filter(|(a, e)| {
+            a.iter()
+                .zip(*e)
+                .any(|(a, e)| a.abs_diff(*e) > CHANNEL_TOLERANCE)
+        })
+        .count();
+
+    Ok(num_diffs as f64 / num_pixels as f64)
+}
+
+/// Decodes the PNG into the width, the height, and the RGBA8 pixels.
+fn decode_png(data: &[u8]) -> Result<(u32, u32, Vec<u8>), png::DecodingError> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+
+    // Convert to RGBA so that the images with different color types can be compared
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        // normalize_to_color8() expands the palette
+        png::ColorType::Indexed => unreachable!(),
+    };
+
+    Ok((info.width, info.height, pixels))
+}
@@ -1,27 +1,96 @@
-use vellogd_protocol::graphics_device_client::GraphicsDeviceClient;
+mod conformance;
+
+use std::path::PathBuf;
+
 use vellogd_protocol::*;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+
+// Used when no server is found in the discovery directory, e.g. a server
+// started with --address [::1]:50051 that couldn't write the discovery file
+const DEFAULT_SERVER: &str = "http://[::1]:50051";
+
+// The file formats the export subcommand supports, by the extension
+const EXPORT_EXTENSIONS: [&str; 3] = ["png", "svg", "pdf"];
+
+fn parse_export_path(x: &str) -> Result<String, String> {
+    let extension = std::path::Path::new(x).extension().and_then(|x| x.to_str());
+    match extension {
+        Some(extension) if EXPORT_EXTENSIONS.contains(&extension) => Ok(x.to_string()),
+        _ => Err(format!(
+            "unsupported file format (the extension must be one of {})",
+            EXPORT_EXTENSIONS.join(", ")
+        )),
+    }
+}
+
+fn server_address(server: Option<String>) -> String {
+    if let Some(server) = server {
+        return server;
+    }
+
+    match discovery::find_server() {
+        Some(entry) => {
+            eprintln!("Using the server of pid {}: {}", entry.pid, entry.address);
+            entry.address
+        }
+        None => {
+            eprintln!("No server is found in the discovery directory. Trying {DEFAULT_SERVER}");
+            DEFAULT_SERVER.to_string()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ResizeModeArg {
+    None,
+    Notify,
+    Scale,
+    Letterbox,
+}
+
+impl From<ResizeModeArg> for ResizeMode {
+    fn from(value: ResizeModeArg) -> Self {
+        match value {
+            ResizeModeArg::None => ResizeMode::None,
+            ResizeModeArg::Notify => ResizeMode::Notify,
+            ResizeModeArg::Scale => ResizeMode::Scale,
+            ResizeModeArg::Letterbox => ResizeMode::Letterbox,
+        }
+    }
+}
 
-fn hex_color_to_u32<T: AsRef<str>>(x: T) -> u32 {
-    let x = x.as_ref();
-    let x_parsed = u32::from_str_radix(x, 16).unwrap();
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum AntialiasingArg {
+    Area,
+    Msaa8,
+    Msaa16,
+}
 
-    match x.len() {
-        4 => {
-            let a = x_parsed & 0xf;
-            let b = (x_parsed >> 4) & 0xf;
-            let g = (x_parsed >> 8) & 0xf;
-            let r = (x_parsed >> 12) & 0xf;
-            r + (r << 4) + (g << 8) + (g << 12) + (b << 16) + (b << 20) + (a << 24) + (a << 28)
+impl From<AntialiasingArg> for AntialiasingMethod {
+    fn from(value: AntialiasingArg) -> Self {
+        match value {
+            AntialiasingArg::Area => AntialiasingMethod::Area,
+            AntialiasingArg::Msaa8 => AntialiasingMethod::Msaa8,
+            AntialiasingArg::Msaa16 => AntialiasingMethod::Msaa16,
         }
-        3 => {
-            let b = x_parsed & 0xf;
-            let g = (x_parsed >> 4) & 0xf;
-            let r = (x_parsed >> 8) & 0xf;
-            r + (r << 4) + (g << 8) + (g << 12) + (b << 16) + (b << 20) + 0xff000000_u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum WidthUnitArg {
+    Pixel,
+    Lwd,
+    Point,
+}
+
+impl From<WidthUnitArg> for LineWidthUnit {
+    fn from(value: WidthUnitArg) -> Self {
+        match value {
+            WidthUnitArg::Pixel => LineWidthUnit::Pixel,
+            WidthUnitArg::Lwd => LineWidthUnit::Lwd,
+            WidthUnitArg::Point => LineWidthUnit::Point,
         }
-        _ => panic!("invalid color format"),
     }
 }
 
@@ -30,15 +99,42 @@ fn hex_color_to_u32<T: AsRef<str>>(x: T) -> u32 {
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// The ID of the target device
+    #[arg(long, global = true, default_value_t = 0)]
+    device: u32,
+
+    /// The unit of the line widths
+    #[arg(long, global = true, value_enum, default_value_t = WidthUnitArg::Pixel)]
+    width_unit: WidthUnitArg,
+
+    /// The address of vellogd-server, e.g. "http://[::1]:50051" or
+    /// "unix:///path/to/socket". If not specified, the most recently started
+    /// server in the discovery directory is used.
+    #[arg(long, global = true)]
+    server: Option<String>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    /// Open a new device and print its ID
+    #[command()]
+    Open {},
+
     #[command()]
     Close {},
 
+    /// Start a new page
     #[command()]
-    Clear {},
+    Clear {
+        /// The background color (e.g. "fff" or "0000" for transparent)
+        #[arg(long, value_parser = parse_hex_color)]
+        bg: Option<u32>,
+        #[arg(long, default_value_t = 0)]
+        width: u32,
+        #[arg(long, default_value_t = 0)]
+        height: u32,
+    },
 
     #[command()]
     Circle {
@@ -50,10 +146,10 @@ enum Commands {
         radius: f64,
         #[arg(long, short, default_value_t = 8.0)]
         width: f64,
-        #[arg(long, short, default_value = "999")]
-        fill: String,
-        #[arg(long, short, default_value = "000")]
-        color: String,
+        #[arg(long, short, default_value = "999", value_parser = parse_hex_color)]
+        fill: u32,
+        #[arg(long, short, default_value = "000", value_parser = parse_hex_color)]
+        color: u32,
     },
 
     #[command()]
@@ -68,8 +164,8 @@ enum Commands {
         y1: f64,
         #[arg(long, short, default_value_t = 8.0)]
         width: f64,
-        #[arg(long, short, default_value = "000")]
-        color: String,
+        #[arg(long, short, default_value = "000", value_parser = parse_hex_color)]
+        color: u32,
     },
 
     #[command()]
@@ -78,8 +174,8 @@ enum Commands {
         pos: Vec<f64>,
         #[arg(long, short, default_value_t = 8.0)]
         width: f64,
-        #[arg(long, short, default_value = "000")]
-        color: String,
+        #[arg(long, short, default_value = "000", value_parser = parse_hex_color)]
+        color: u32,
     },
 
     #[command()]
@@ -88,10 +184,10 @@ enum Commands {
         pos: Vec<f64>,
         #[arg(long, short, default_value_t = 8.0)]
         width: f64,
-        #[arg(long, short, default_value = "999")]
-        fill: String,
-        #[arg(long, short, default_value = "000")]
-        color: String,
+        #[arg(long, short, default_value = "999", value_parser = parse_hex_color)]
+        fill: u32,
+        #[arg(long, short, default_value = "000", value_parser = parse_hex_color)]
+        color: u32,
     },
 
     #[command()]
@@ -102,8 +198,8 @@ enum Commands {
         y: f64,
         #[arg(default_value = "🌶")] // to test emoji (cannot input from Powershell)
         text: String,
-        #[arg(long, short, default_value = "000")]
-        color: String,
+        #[arg(long, short, default_value = "000", value_parser = parse_hex_color)]
+        color: u32,
         #[arg(long, short, default_value_t = 50.0)]
         size: f32,
         #[arg(long, default_value_t = 1.0)]
@@ -118,6 +214,127 @@ enum Commands {
         #[arg(long, default_value_t = 0.0)]
         hadj: f32,
     },
+
+    /// Render the pending drawing operations immediately
+    #[command()]
+    Flush {},
+
+    /// Change the hold level (e.g. 1 to hold, -1 to flush) and print the new level
+    #[command()]
+    HoldFlush {
+        #[arg(allow_negative_numbers = true)]
+        level: i32,
+    },
+
+    /// Draw many small circles and report the throughput
+    #[command()]
+    Bench {
+        /// The number of circles
+        #[arg(long, short, default_value_t = 100_000)]
+        count: u32,
+        /// The number of requests in flight. The circles may be drawn out of
+        /// order if this is larger than 1.
+        #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
+    },
+
+    /// Draw every combination of the line ends and joins, and compare the
+    /// PNG images with the reference images. Run the server with --headless.
+    #[command()]
+    Conformance {
+        /// The directory to write the results
+        #[arg(long, default_value = "conformance")]
+        output: PathBuf,
+        /// The directory of the reference images
+        #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/conformance-reference"))]
+        reference: PathBuf,
+        /// Overwrite the reference images with the results
+        #[arg(long)]
+        update: bool,
+        /// The maximum ratio of the different pixels to pass
+        #[arg(long, default_value_t = 0.005)]
+        max_diff_ratio: f64,
+    },
+
+    /// Print the size and the scale factor of the device
+    #[command()]
+    Info {},
+
+    /// Set how the device reacts when the window is resized
+    #[command()]
+    ResizeMode {
+        #[arg(value_enum)]
+        mode: ResizeModeArg,
+    },
+
+    /// Set the coordinates of the edges of the page (e.g. 0 1 0 1 for the
+    /// origin at the bottom-left). All 0 resets to the pixels.
+    #[command()]
+    Extents {
+        #[arg(allow_negative_numbers = true)]
+        left: f64,
+        #[arg(allow_negative_numbers = true)]
+        right: f64,
+        #[arg(allow_negative_numbers = true)]
+        bottom: f64,
+        #[arg(allow_negative_numbers = true)]
+        top: f64,
+    },
+
+    /// Print the adapters and the shaders used by the server
+    #[command()]
+    Status {},
+
+    /// Change the render options of the server. The options not specified are
+    /// left unchanged.
+    #[command()]
+    RenderOptions {
+        #[arg(long, value_enum)]
+        antialiasing: Option<AntialiasingArg>,
+        #[arg(long)]
+        vsync: Option<bool>,
+        /// "default", "low-power", "high-performance", "software", or a part
+        /// of the adapter name
+        #[arg(long)]
+        adapter: Option<String>,
+    },
+
+    /// Wait for a click on the window and print the position
+    #[command()]
+    Locator {},
+
+    /// Print the notifications from the device until interrupted
+    #[command()]
+    Watch {},
+
+    /// Export the current page. The format is determined by the extension of
+    /// the output (.png, .svg, or .pdf). PDF contains all the pages, not only
+    /// the current one.
+    #[command()]
+    Export {
+        /// The output file. The format is determined by the extension (png,
+        /// svg, or pdf).
+        #[arg(value_parser = parse_export_path)]
+        output: String,
+        /// (PNG only) Width in device coordinates (0 means the current width of the device)
+        #[arg(long, default_value_t = 0)]
+        width: u32,
+        /// (PNG only) Height in device coordinates (0 means the current height of the device)
+        #[arg(long, default_value_t = 0)]
+        height: u32,
+        /// (PNG only) Scale of the image
+        #[arg(long, short, default_value_t = 1.0)]
+        scale: f64,
+        /// (PNG only) Don't fill the background
+        #[arg(long)]
+        transparent: bool,
+        /// (SVG only) Convert text to paths
+        #[arg(long)]
+        text_as_path: bool,
+        /// Let the server write the file (the path is interpreted on the server side)
+        #[arg(long)]
+        on_server: bool,
+    },
 }
 
 #[tokio::main]
@@ -125,11 +342,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Cli::parse();
     println!("{args:?}");
 
-    let mut client = GraphicsDeviceClient::connect("http://[::1]:50051").await?;
+    let mut client = vellogd_protocol::connect(&server_address(args.server)).await?;
+
+    let device_id = args.device;
 
     let response = match args.command {
-        Commands::Close {} => client.close_window(Empty {}).await,
-        Commands::Clear {} => client.new_page(Empty {}).await,
+        Commands::Open {} => {
+            let response = client.open_device(OpenDeviceRequest {}).await?;
+            println!("Opened device {}", response.into_inner().device_id);
+            return Ok(());
+        }
+        Commands::Close {} => client.close_window(CloseWindowRequest { device_id }).await,
+        Commands::Clear { bg, width, height } => {
+            let request = tonic::Request::new(NewPageRequest {
+                device_id,
+                background_color: bg,
+                width,
+                height,
+                background_color_rgba: None,
+            });
+            client.new_page(request).await
+        }
 
         Commands::Circle {
             cx,
@@ -139,22 +372,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             fill,
             color,
         } => {
-            let fill_color = hex_color_to_u32(fill);
-            let fill_color = if fill_color != 0 {
-                Some(fill_color)
-            } else {
-                None
-            };
+            let fill_color = if fill != 0 { Some(fill) } else { None };
 
-            let stroke_color = hex_color_to_u32(color);
-            let stroke_params = if stroke_color != 0 {
+            let stroke_params = if color != 0 {
                 Some(StrokeParameters {
-                    color: stroke_color,
+                    color,
                     width,
-                    linetype: 1,
+                    linetype: 0, // LTY_SOLID
                     join: 1,
                     miter_limit: 1.0,
                     cap: 1,
+                    width_unit: LineWidthUnit::from(args.width_unit).into(),
+                    color_rgba: None,
                 })
             } else {
                 None
@@ -166,6 +395,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 radius,
                 fill_color,
                 stroke_params,
+                device_id,
+                fill_color_rgba: None,
             });
             client.draw_circle(request).await
         }
@@ -178,15 +409,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             width,
             color,
         } => {
-            let color = hex_color_to_u32(color);
             let stroke_params = if color != 0 {
                 Some(StrokeParameters {
                     color,
                     width,
-                    linetype: 1,
+                    linetype: 0, // LTY_SOLID
                     join: 1,
                     miter_limit: 1.0,
                     cap: 1,
+                    width_unit: LineWidthUnit::from(args.width_unit).into(),
+                    color_rgba: None,
                 })
             } else {
                 None
@@ -198,6 +430,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 x1,
                 y1,
                 stroke_params,
+                device_id,
             });
             client.draw_line(request).await
         }
@@ -215,15 +448,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 y.push(p[1]);
             }
 
-            let color = hex_color_to_u32(color);
             let stroke_params = if color != 0 {
                 Some(StrokeParameters {
                     color,
                     width,
-                    linetype: 1,
+                    linetype: 0, // LTY_SOLID
                     join: 1,
                     miter_limit: 1.0,
                     cap: 1,
+                    width_unit: LineWidthUnit::from(args.width_unit).into(),
+                    color_rgba: None,
                 })
             } else {
                 None
@@ -233,6 +467,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 x,
                 y,
                 stroke_params,
+                device_id,
             });
             client.draw_polyline(request).await
         }
@@ -255,22 +490,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 y.push(p[1]);
             }
 
-            let fill_color = hex_color_to_u32(fill);
-            let fill_color = if fill_color != 0 {
-                Some(fill_color)
-            } else {
-                None
-            };
+            let fill_color = if fill != 0 { Some(fill) } else { None };
 
-            let color = hex_color_to_u32(color);
             let stroke_params = if color != 0 {
                 Some(StrokeParameters {
                     color,
                     width,
-                    linetype: 1,
+                    linetype: 0, // LTY_SOLID
                     join: 1,
                     miter_limit: 1.0,
                     cap: 1,
+                    width_unit: LineWidthUnit::from(args.width_unit).into(),
+                    color_rgba: None,
                 })
             } else {
                 None
@@ -281,6 +512,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 y,
                 fill_color,
                 stroke_params,
+                device_id,
+                fill_color_rgba: None,
             });
             client.draw_polygon(request).await
         }
@@ -297,7 +530,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             angle,
             hadj,
         } => {
-            let color = hex_color_to_u32(color);
             let request = tonic::Request::new(DrawTextRequest {
                 x,
                 y,
@@ -309,9 +541,221 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 family,
                 angle: angle.to_radians(),
                 hadj,
+                device_id,
+                color_rgba: None,
             });
             client.draw_text(request).await
         }
+
+        Commands::Flush {} => {
+            let request = tonic::Request::new(FlushRequest { device_id });
+            client.flush(request).await
+        }
+
+        Commands::HoldFlush { level } => {
+            let request = tonic::Request::new(HoldFlushRequest { device_id, level });
+            let response = client.hold_flush(request).await?.into_inner();
+            println!("Hold level: {}", response.level);
+            return Ok(());
+        }
+
+        Commands::Bench { count, concurrency } => {
+            let request = tonic::Request::new(GetDeviceInfoRequest { device_id });
+            let info = client.get_device_info(request).await?.into_inner();
+
+            let start = std::time::Instant::now();
+            // Pipeline the requests so that the round trips don't dominate
+            let mut tasks = tokio::task::JoinSet::new();
+            for i in 0..count {
+                if tasks.len() >= concurrency as usize {
+                    if let Some(result) = tasks.join_next().await {
+                        result??;
+                    }
+                }
+                // Scatter the circles over the device with a cheap pseudo-random sequence
+                let cx = (i as f64 * 0.618_033_988_7).fract() * info.width as f64;
+                let cy = (i as f64 * 0.754_877_666_2).fract() * info.height as f64;
+                let request = tonic::Request::new(DrawCircleRequest {
+                    cx,
+                    cy,
+                    radius: 2.0,
+                    fill_color: Some(0xff_80_40_20 | (i % 256)),
+                    stroke_params: None,
+                    device_id,
+                    fill_color_rgba: None,
+                });
+                let mut client = client.clone();
+                tasks.spawn(async move { client.draw_circle(request).await });
+            }
+            while let Some(result) = tasks.join_next().await {
+                result??;
+            }
+            let sent = start.elapsed();
+
+            let request = tonic::Request::new(FlushRequest { device_id });
+            client.flush(request).await?;
+            let flushed = start.elapsed();
+
+            println!(
+                "Sent {count} circles in {:.3}s ({:.0} ops/s), rendered in {:.3}s ({:.0} ops/s)",
+                sent.as_secs_f64(),
+                count as f64 / sent.as_secs_f64(),
+                flushed.as_secs_f64(),
+                count as f64 / flushed.as_secs_f64(),
+            );
+            return Ok(());
+        }
+
+        Commands::Conformance {
+            output,
+            reference,
+            update,
+            max_diff_ratio,
+        } => {
+            let options = conformance::Options {
+                output: &output,
+                reference: &reference,
+                update,
+                max_diff_ratio,
+            };
+            return conformance::run(&mut client, device_id, options).await;
+        }
+
+        Commands::Info {} => {
+            let request = tonic::Request::new(GetDeviceInfoRequest { device_id });
+            let info = client.get_device_info(request).await?.into_inner();
+            println!("{info:?}");
+            return Ok(());
+        }
+
+        Commands::ResizeMode { mode } => {
+            let request = tonic::Request::new(SetResizeModeRequest {
+                device_id,
+                mode: ResizeMode::from(mode).into(),
+            });
+            client.set_resize_mode(request).await
+        }
+
+        Commands::Extents {
+            left,
+            right,
+            bottom,
+            top,
+        } => {
+            let request = tonic::Request::new(SetDeviceExtentsRequest {
+                device_id,
+                left,
+                right,
+                bottom,
+                top,
+            });
+            client.set_device_extents(request).await
+        }
+
+        Commands::Status {} => {
+            let request = tonic::Request::new(GetStatusRequest {});
+            let status = client.get_status(request).await?.into_inner();
+            println!("{status:#?}");
+            return Ok(());
+        }
+
+        Commands::RenderOptions {
+            antialiasing,
+            vsync,
+            adapter,
+        } => {
+            let request = tonic::Request::new(SetRenderOptionsRequest {
+                antialiasing: antialiasing
+                    .map_or(AntialiasingMethod::Unspecified, AntialiasingMethod::from)
+                    .into(),
+                vsync,
+                adapter,
+            });
+            client.set_render_options(request).await
+        }
+
+        Commands::Locator {} => {
+            let request = tonic::Request::new(LocatorRequest { device_id });
+            let response = client.locator(request).await?.into_inner();
+            if response.selected {
+                println!("x: {}, y: {}", response.x, response.y);
+            } else {
+                println!("Cancelled");
+            }
+            return Ok(());
+        }
+
+        Commands::Watch {} => {
+            let request = tonic::Request::new(WatchEventsRequest {
+                device_id: Some(device_id),
+            });
+            let mut stream = client.watch_events(request).await?.into_inner();
+            while let Some(notification) = stream.message().await? {
+                println!("{notification:?}");
+            }
+            return Ok(());
+        }
+
+        Commands::Export {
+            output,
+            width,
+            height,
+            scale,
+            transparent,
+            text_as_path,
+            on_server,
+        } => {
+            let path = on_server.then(|| output.clone());
+
+            let extension = std::path::Path::new(&output)
+                .extension()
+                .and_then(|x| x.to_str());
+            let data = match extension {
+                Some("png") => {
+                    let request = tonic::Request::new(ExportPngRequest {
+                        path,
+                        width,
+                        height,
+                        scale,
+                        transparent,
+                        device_id,
+                    });
+                    client.export_png(request).await?.into_inner().data
+                }
+                Some("svg") => {
+                    let request = tonic::Request::new(ExportSvgRequest {
+                        path,
+                        text_as_path,
+                        device_id,
+                    });
+                    client
+                        .export_svg(request)
+                        .await?
+                        .into_inner()
+                        .data
+                        .into_bytes()
+                }
+                Some("pdf") => {
+                    let request = tonic::Request::new(ExportPdfRequest { path, device_id });
+                    let response = client.export_pdf(request).await?.into_inner();
+                    if response.discarded_pages > 0 {
+                        eprintln!(
+                            "Warning: the oldest {} pages were already discarded",
+                            response.discarded_pages
+                        );
+                    }
+                    response.data
+                }
+                _ => return Err(format!("unsupported file format: {output}").into()),
+            };
+
+            if !on_server {
+                std::fs::write(&output, data)?;
+            }
+            println!("Exported to {output}");
+
+            return Ok(());
+        }
     }?;
 
     println!("RESPONSE={:?}", response);
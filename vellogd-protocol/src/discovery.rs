@@ -0,0 +1,345 @@
+// Discovery of the running servers. Each server writes a small file with its
+// address into the discovery directory, so that the clients can find it
+// without knowing the port in advance. The file is removed when the server
+// exits, and the files left by crashed servers are detected by the process
+// ID.
+//
+// Since a client connects to whatever address the file says, the directory and
+// the files must be owned by the current user and not writable by the others.
+// Otherwise, they are ignored.
+
+use std::path::{Path, PathBuf};
+
+use crate::PROTOCOL_VERSION;
+
+const FILE_EXTENSION: &str = "server";
+
+/// An entry of the discovery directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerEntry {
+    /// The address to pass to [`crate::connect`]
+    pub address: String,
+    pub pid: u32,
+    pub protocol_version: u32,
+}
+
+impl ServerEntry {
+    /// The entry of the current process.
+    pub fn new(address: String) -> Self {
+        Self {
+            address,
+            pid: std::process::id(),
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+
+    fn to_file_content(&self) -> String {
+        format!(
+            "address={}\npid={}\nprotocol_version={}\n",
+            self.address, self.pid, self.protocol_version
+        )
+    }
+
+    fn from_file_content(content: &str) -> Option<Self> {
+        let mut address = None;
+        let mut pid = None;
+        let mut protocol_version = None;
+        for line in content.lines() {
+            match line.split_once('=')? {
+                ("address", v) => address = Some(v.to_string()),
+                ("pid", v) => pid = v.parse().ok(),
+                ("protocol_version", v) => protocol_version = v.parse().ok(),
+                // Ignore unknown keys for the compatibility with newer servers
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            address: address?,
+            pid: pid?,
+            protocol_version: protocol_version?,
+        })
+    }
+}
+
+/// The directory of the discovery files: `$XDG_RUNTIME_DIR/vellogd` if the
+/// variable is set, otherwise a per-user directory in the temporary directory.
+pub fn discovery_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("vellogd"),
+        _ => std::env::temp_dir().join(format!("vellogd-{}", user_id())),
+    }
+}
+
+// Unlike $USER, this is always set and can't be spoofed
+#[cfg(unix)]
+fn user_id() -> String {
+    unsafe { libc::getuid() }.to_string()
+}
+
+// The temporary directory is per-user on Windows
+#[cfg(not(unix))]
+fn user_id() -> String {
+    std::env::var("USERNAME").unwrap_or_default()
+}
+
+/// Fails unless the directory is a real directory owned by the current user
+/// and none of forbidden_mode is set in its permissions.
+fn check_dir(dir: &Path, forbidden_mode: u32) -> std::io::Result<()> {
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(std::io::Error::other(format!(
+            "{} is not a directory",
+            dir.display()
+        )));
+    }
+    check_owner(dir, &metadata, forbidden_mode)
+}
+
+/// Fails if the path is owned by another user or any bit of forbidden_mode is
+/// set in its permissions.
+#[cfg(unix)]
+fn check_owner(
+    path: &Path,
+    metadata: &std::fs::Metadata,
+    forbidden_mode: u32,
+) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    if metadata.uid() != unsafe { libc::getuid() } {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is owned by another user", path.display()),
+        ));
+    }
+    let mode = metadata.mode() & 0o777;
+    if mode & forbidden_mode != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} has too open permissions ({mode:o})", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+// TODO: check the ACL on Windows
+#[cfg(not(unix))]
+fn check_owner(
+    _path: &Path,
+    _metadata: &std::fs::Metadata,
+    _forbidden_mode: u32,
+) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// The discovery file of a running server, which is removed on drop.
+#[derive(Debug)]
+pub struct DiscoveryFile {
+    path: PathBuf,
+}
+
+impl DiscoveryFile {
+    pub fn create(entry: &ServerEntry) -> std::io::Result<Self> {
+        let dir = discovery_dir();
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&dir)?;
+
+        // e.g. another user created the directory (or a symlink) in the shared
+        // temporary directory first. This must be checked before changing the
+        // permissions, which follows symlinks.
+        check_dir(&dir, 0)?;
+        // The mode is not applied if the directory already exists
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+
+        // Write to a temporary file first so that the clients never read a
+        // partially written file.
+        let path = dir.join(format!("{}.{FILE_EXTENSION}", entry.pid));
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, entry.to_file_content())?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DiscoveryFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the servers that are still running, the most recently started
+/// first. The files of the exited servers are removed along the way. The
+/// directory and the files that other users could have written are ignored.
+pub fn list_servers() -> Vec<ServerEntry> {
+    let dir = discovery_dir();
+    if check_dir(&dir, 0o077).is_err() {
+        return Vec::new();
+    }
+    let Ok(dir) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut servers = Vec::new();
+    for path in dir.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.extension() != Some(FILE_EXTENSION.as_ref()) {
+            continue;
+        }
+        let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if !metadata.is_file() || check_owner(&path, &metadata, 0o022).is_err() {
+            continue;
+        }
+        let Some(entry) = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| ServerEntry::from_file_content(&content))
+        else {
+            continue;
+        };
+
+        if !is_process_alive(entry.pid) {
+            let _ = std::fs::remove_file(&path);
+            continue;
+        }
+
+        let modified = metadata.modified().ok();
+        servers.push((modified, entry));
+    }
+
+    servers.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    servers.into_iter().map(|(_, entry)| entry).collect()
+}
+
+/// Returns the most recently started server that speaks the same version of
+/// the protocol.
+pub fn find_server() -> Option<ServerEntry> {
+    list_servers()
+        .into_iter()
+        .find(|entry| entry.protocol_version == PROTOCOL_VERSION)
+}
+
+#[cfg(unix)]
+fn is_process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks the existence. EPERM means the process exists but
+    // belongs to another user.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// TODO: check the process on Windows
+#[cfg(not(unix))]
+fn is_process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(pid: u32, protocol_version: u32) -> ServerEntry {
+        ServerEntry {
+            address: "http://[::1]:50051".to_string(),
+            pid,
+            protocol_version,
+        }
+    }
+
+    #[test]
+    fn test_file_content_round_trip() {
+        let entry = entry(1234, PROTOCOL_VERSION);
+        assert_eq!(
+            ServerEntry::from_file_content(&entry.to_file_content()),
+            Some(entry.clone())
+        );
+
+        // The address can contain '='
+        let entry = ServerEntry {
+            address: "unix:///tmp/a=b/vellogd.sock".to_string(),
+            ..entry
+        };
+        assert_eq!(
+            ServerEntry::from_file_content(&entry.to_file_content()),
+            Some(entry)
+        );
+    }
+
+    #[test]
+    fn test_file_content_malformed() {
+        let cases = [
+            "",
+            // missing keys
+            "pid=1\nprotocol_version=1\n",
+            "address=a\nprotocol_version=1\n",
+            "address=a\npid=1\n",
+            // invalid values
+            "address=a\npid=x\nprotocol_version=1\n",
+            "address=a\npid=1\nprotocol_version=-1\n",
+            // a line without '='
+            "address=a\npid=1\nprotocol_version=1\ngarbage\n",
+        ];
+        for content in cases {
+            assert_eq!(ServerEntry::from_file_content(content), None, "{content:?}");
+        }
+    }
+
+    #[test]
+    fn test_file_content_unknown_keys() {
+        let content = "address=a\nfoo=bar\npid=1\nprotocol_version=2\n=\n";
+        assert_eq!(
+            ServerEntry::from_file_content(content),
+            Some(ServerEntry {
+                address: "a".to_string(),
+                pid: 1,
+                protocol_version: 2,
+            })
+        );
+    }
+
+    // This is the only test that touches the environment variable
+    #[cfg(unix)]
+    #[test]
+    fn test_find_server_protocol_version() {
+        let runtime_dir = std::env::temp_dir().join(format!("vellogd-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&runtime_dir);
+        std::fs::create_dir_all(&runtime_dir).unwrap();
+        std::env::set_var("XDG_RUNTIME_DIR", &runtime_dir);
+
+        let compatible = entry(std::process::id(), PROTOCOL_VERSION);
+        let file = DiscoveryFile::create(&compatible).unwrap();
+
+        // A newer server that started later. pid 1 is always alive.
+        let incompatible = entry(1, PROTOCOL_VERSION + 1);
+        let path = discovery_dir().join(format!("1.{FILE_EXTENSION}"));
+        std::fs::write(&path, incompatible.to_file_content()).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+
+        assert_eq!(list_servers(), [incompatible, compatible.clone()]);
+        assert_eq!(find_server(), Some(compatible));
+
+        drop(file);
+        std::fs::remove_dir_all(&runtime_dir).unwrap();
+    }
+}
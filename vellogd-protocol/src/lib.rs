@@ -3,3 +3,126 @@ mod graphics_device {
 }
 
 pub use graphics_device::*;
+
+pub mod discovery;
+
+/// The version of the protocol, which is bumped on incompatible changes. The
+/// clients use this to find a compatible server (see [`discovery`]).
+pub const PROTOCOL_VERSION: u32 = 1;
+
+use graphics_device_client::GraphicsDeviceClient;
+use tonic::transport::{Channel, Endpoint};
+
+/// Connects to the server. The address is either a URI like
+/// `http://[::1]:50051` or a path to the Unix domain socket like
+/// `unix:///run/user/1000/vellogd.sock` (on Unix only).
+pub async fn connect(
+    address: &str,
+) -> Result<GraphicsDeviceClient<Channel>, tonic::transport::Error> {
+    #[cfg(unix)]
+    if let Some(path) = address.strip_prefix("unix://") {
+        let path = path.to_string();
+        // The URI is required by Endpoint, but not used by the connector
+        let channel = Endpoint::from_static("http://[::]:50051")
+            .connect_with_connector(tower::service_fn(move |_| {
+                let path = path.clone();
+                async move {
+                    let stream = tokio::net::UnixStream::connect(path).await?;
+                    Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(stream))
+                }
+            }))
+            .await?;
+        return Ok(GraphicsDeviceClient::new(channel));
+    }
+
+    let channel = Endpoint::from_shared(address.to_string())?
+        .connect()
+        .await?;
+    Ok(GraphicsDeviceClient::new(channel))
+}
+
+/// Packs the color into the format of the `uint32` color fields, which is the
+/// same as R's (cf. R_RGBA() in R_ext/GraphicsDevice.h): red in the lowest 8
+/// bits, then green, blue, and alpha in the highest 8 bits.
+pub fn pack_rgba(rgba: [u8; 4]) -> u32 {
+    u32::from_le_bytes(rgba)
+}
+
+/// Unpacks the `uint32` color into `[red, green, blue, alpha]`.
+pub fn unpack_rgba(x: u32) -> [u8; 4] {
+    x.to_le_bytes()
+}
+
+/// Parses a hex color like CSS (RGB, RGBA, RRGGBB, or RRGGBBAA, optionally
+/// prefixed with `#`) into the `uint32` format.
+pub fn parse_hex_color(x: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid color {x:?} (must be RGB, RGBA, RRGGBB, or RRGGBBAA in hex)");
+
+    let digits = x
+        .strip_prefix('#')
+        .unwrap_or(x)
+        .chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(invalid)?;
+
+    let rgba = match *digits.as_slice() {
+        // a single digit is repeated (e.g. "f" is "ff")
+        [r, g, b] => [r * 17, g * 17, b * 17, 0xff],
+        [r, g, b, a] => [r * 17, g * 17, b * 17, a * 17],
+        [r1, r2, g1, g2, b1, b2] => [r1 << 4 | r2, g1 << 4 | g2, b1 << 4 | b2, 0xff],
+        [r1, r2, g1, g2, b1, b2, a1, a2] => {
+            [r1 << 4 | r2, g1 << 4 | g2, b1 << 4 | b2, a1 << 4 | a2]
+        }
+        _ => return Err(invalid()),
+    };
+
+    Ok(pack_rgba(rgba))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_rgba() {
+        assert_eq!(pack_rgba([0xff, 0, 0, 0xff]), 0xff0000ff);
+        assert_eq!(pack_rgba([0x12, 0x34, 0x56, 0x78]), 0x78563412);
+        assert_eq!(unpack_rgba(0xff0000ff), [0xff, 0, 0, 0xff]);
+        assert_eq!(unpack_rgba(0x78563412), [0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[test]
+    fn test_parse_hex_color() {
+        let cases = [
+            ("f00", [0xff, 0, 0, 0xff]),
+            ("f008", [0xff, 0, 0, 0x88]),
+            ("123456", [0x12, 0x34, 0x56, 0xff]),
+            ("12345678", [0x12, 0x34, 0x56, 0x78]),
+            ("#123456", [0x12, 0x34, 0x56, 0xff]),
+            ("#AbCdEf", [0xab, 0xcd, 0xef, 0xff]),
+        ];
+        for (x, rgba) in cases {
+            assert_eq!(parse_hex_color(x), Ok(pack_rgba(rgba)), "{x}");
+        }
+    }
+
+    #[test]
+    fn test_parse_hex_color_invalid() {
+        for x in [
+            "",
+            "#",
+            "12",
+            "12345",
+            "1234567",
+            "123456789",
+            "ggg",
+            "##fff",
+            " fff",
+            "+ff",
+        ] {
+            let err = parse_hex_color(x).unwrap_err();
+            assert!(err.starts_with("invalid color"), "{err}");
+        }
+    }
+}
@@ -0,0 +1,441 @@
+// The state of each graphics device. A device has its own window, pages and
+// scene, while the GPU resources (e.g. Renderer) are shared among the devices.
+
+use std::{sync::Arc, time::Instant};
+
+use vello::{
+    kurbo::{Affine, Point, Vec2},
+    peniko::Color,
+    Scene,
+};
+use vellogd_protocol::{LocatorResponse, ResizeMode};
+use winit::window::{Window, WindowId};
+
+use crate::{
+    display_list::{DisplayItem, DisplayList},
+    RenderState, MAX_PAGES,
+};
+
+/// The settings of new devices, specified by the command-line flags.
+#[derive(Debug, Clone)]
+pub(crate) struct DeviceConfig {
+    // The initial size of the window in logical pixels
+    pub(crate) size: (u32, u32),
+    pub(crate) title: String,
+    // The background color of a new page when NewPage doesn't specify it
+    pub(crate) background_color: Color,
+}
+
+pub(crate) struct DeviceState<'a> {
+    id: u32,
+    pub(crate) config: DeviceConfig,
+    pub(crate) render_state: RenderState<'a>,
+    // The history of the pages. The last one is the current page, where new
+    // drawing goes to.
+    pages: Vec<DisplayList>,
+    // The number of the pages discarded from the history because of
+    // MAX_PAGES, so that the exports can report the truncation
+    discarded_pages: u32,
+    // The first page is a placeholder until NewPage is requested. It's
+    // replaced by the first NewPage if nothing is drawn on it.
+    placeholder_page: bool,
+    // The index of the page shown in the window. The scene is built from this
+    // page.
+    viewing_page: usize,
+    pub(crate) scene: Scene,
+    pub(crate) resize_mode: ResizeMode,
+    // The ratio of the physical pixels to the logical pixels (e.g. 2.0 on
+    // Retina displays). The clients always draw in the logical coordinates.
+    pub(crate) scale_factor: f64,
+    // Zoom and pan by the user, in the logical coordinates of the window
+    zoom: Affine,
+    // The last known position of the mouse cursor in logical pixels
+    cursor_position: Option<Point>,
+    drag: Option<Drag>,
+    // The pending Locator request, which is resolved by the next click
+    locator_reply: Option<tokio::sync::oneshot::Sender<LocatorResponse>>,
+    // The time of the last drawing operation that is not rendered yet. None
+    // if the window is up to date.
+    pub(crate) last_change: Option<Instant>,
+    // The time of the first drawing operation that is not rendered yet
+    pub(crate) first_change: Option<Instant>,
+    // While the hold level is larger than 0 (i.e. dev.hold() in R), the window
+    // keeps showing held_scene, the snapshot of the scene at the time of hold.
+    hold_level: u32,
+    held_scene: Option<Scene>,
+    // The coordinate system declared by the client. None means the logical
+    // pixels of the page.
+    extents: Option<Extents>,
+}
+
+/// The coordinates of the edges of the page, like DevDesc in R. If bottom is
+/// larger than top, the y axis points down.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Extents {
+    pub(crate) left: f64,
+    pub(crate) right: f64,
+    pub(crate) bottom: f64,
+    pub(crate) top: f64,
+}
+
+impl Extents {
+    /// The transform from these coordinates to the pixels of a page of the
+    /// size.
+    fn to_pixels(self, (width, height): (u32, u32)) -> Affine {
+        Affine::translate((-self.left, -self.top)).then_scale_non_uniform(
+            width as f64 / (self.right - self.left),
+            height as f64 / (self.bottom - self.top),
+        )
+    }
+}
+
+struct Drag {
+    start: Point,
+    // If false, this is (so far) a click, not a drag
+    moved: bool,
+}
+
+// A drag shorter than this (in logical pixels) is treated as a click
+const CLICK_TOLERANCE: f64 = 3.0;
+
+impl<'a> DeviceState<'a> {
+    pub(crate) fn new(id: u32, config: DeviceConfig) -> Self {
+        Self {
+            id,
+            render_state: RenderState::Suspended(None),
+            pages: vec![DisplayList::new(config.size, config.background_color)],
+            discarded_pages: 0,
+            placeholder_page: true,
+            viewing_page: 0,
+            scene: Scene::new(),
+            resize_mode: ResizeMode::None,
+            scale_factor: 1.0,
+            zoom: Affine::IDENTITY,
+            cursor_position: None,
+            drag: None,
+            locator_reply: None,
+            last_change: None,
+            first_change: None,
+            hold_level: 0,
+            held_scene: None,
+            extents: None,
+            config,
+        }
+    }
+
+    pub(crate) fn window(&self) -> Option<&Arc<Window>> {
+        match &self.render_state {
+            RenderState::Active(state) => Some(&state.window),
+            RenderState::Suspended(window) => window.as_ref(),
+        }
+    }
+
+    pub(crate) fn window_id(&self) -> Option<WindowId> {
+        self.window().map(|w| w.id())
+    }
+
+    pub(crate) fn request_redraw(&self) {
+        if let RenderState::Active(state) = &self.render_state {
+            state.window.request_redraw();
+        }
+    }
+
+    /// Starts a new page. If the size or the background color is not
+    /// specified, the current size of the device or the default background
+    /// color is used.
+    pub(crate) fn new_page(&mut self, size: Option<(u32, u32)>, background_color: Option<Color>) {
+        let page = DisplayList::new(
+            size.unwrap_or_else(|| self.size()),
+            background_color.unwrap_or(self.config.background_color),
+        );
+
+        // Only the placeholder is replaced. A blank page requested by the
+        // client is kept (e.g. plot.new() without drawing anything)
+        if self.placeholder_page && self.current_page().items().is_empty() {
+            self.pages.pop();
+        }
+        self.placeholder_page = false;
+        self.pages.push(page);
+        if self.pages.len() > MAX_PAGES {
+            self.pages.remove(0);
+            self.discarded_pages += 1;
+        }
+        self.viewing_page = self.pages.len() - 1;
+        self.scene.reset();
+        self.zoom = Affine::IDENTITY;
+        self.update_title();
+    }
+
+    /// Resizes the placeholder page to the window, unless anything is drawn
+    /// on it.
+    pub(crate) fn fit_placeholder_page(&mut self) {
+        if !self.placeholder_page || !self.current_page().items().is_empty() {
+            return;
+        }
+
+        let page = DisplayList::new(self.size(), self.current_page().background_color());
+        *self
+            .pages
+            .last_mut()
+            .expect("there's always at least one page") = page;
+        self.scene.reset();
+    }
+
+    pub(crate) fn push_display_item(&mut self, item: DisplayItem) {
+        // New drawing always goes to the newest page
+        self.show_page(self.pages.len() - 1);
+
+        // The scene is updated incrementally instead of rebuilding from the display list
+        item.draw(&mut self.scene, Affine::IDENTITY);
+        self.pages
+            .last_mut()
+            .expect("there's always at least one page")
+            .push(item);
+    }
+
+    // Navigation is ignored while held, as the window keeps showing
+    // held_scene regardless of the page.
+
+    pub(crate) fn show_previous_page(&mut self) {
+        if !self.is_held() {
+            self.show_page(self.viewing_page.saturating_sub(1));
+        }
+    }
+
+    pub(crate) fn show_next_page(&mut self) {
+        if !self.is_held() {
+            self.show_page(self.viewing_page + 1);
+        }
+    }
+
+    /// Switches the page shown in the window. Out-of-range index is ignored.
+    fn show_page(&mut self, index: usize) {
+        if index == self.viewing_page || index >= self.pages.len() {
+            return;
+        }
+
+        self.viewing_page = index;
+        self.pages[index].build_scene(&mut self.scene, Affine::IDENTITY);
+        self.update_title();
+        self.request_redraw();
+    }
+
+    /// Changes the hold level by delta and returns the new level.
+    pub(crate) fn hold_flush(&mut self, delta: i32) -> u32 {
+        self.hold_level = self.hold_level.saturating_add_signed(delta);
+
+        if self.hold_level == 0 {
+            // Present the accumulated scene
+            if self.held_scene.take().is_some() {
+                self.update_title();
+                self.request_redraw();
+            }
+        } else if self.held_scene.is_none() {
+            self.held_scene = Some(self.scene.clone());
+        }
+
+        self.hold_level
+    }
+
+    pub(crate) fn is_held(&self) -> bool {
+        self.hold_level > 0
+    }
+
+    /// The scene to show in the window.
+    pub(crate) fn displayed_scene(&self) -> &Scene {
+        self.held_scene.as_ref().unwrap_or(&self.scene)
+    }
+
+    /// Shows the current page in the title. While held, the title is kept
+    /// in sync with held_scene and is updated when the hold is released.
+    pub(crate) fn update_title(&self) {
+        if self.is_held() {
+            return;
+        }
+        if let RenderState::Active(state) = &self.render_state {
+            state.window.set_title(&format!(
+                "{} [device {}] (page {} of {})",
+                self.config.title,
+                self.id,
+                self.viewing_page + 1,
+                self.pages.len()
+            ));
+        }
+    }
+
+    pub(crate) fn pages(&self) -> &[DisplayList] {
+        &self.pages
+    }
+
+    /// The number of the oldest pages discarded from the history.
+    pub(crate) fn discarded_pages(&self) -> u32 {
+        self.discarded_pages
+    }
+
+    pub(crate) fn current_page(&self) -> &DisplayList {
+        self.pages.last().expect("there's always at least one page")
+    }
+
+    /// The page shown in the window, which is not always the current page.
+    pub(crate) fn viewing_page(&self) -> &DisplayList {
+        &self.pages[self.viewing_page]
+    }
+
+    pub(crate) fn set_extents(&mut self, extents: Option<Extents>) {
+        self.extents = extents;
+    }
+
+    /// The transform from the coordinates of the drawing requests to the
+    /// pixels of the current page. This is applied when the item is added, so
+    /// the display list is always in pixels.
+    pub(crate) fn device_transform(&self) -> Affine {
+        self.page_transform(self.current_page())
+    }
+
+    fn page_transform(&self, page: &DisplayList) -> Affine {
+        match self.extents {
+            Some(extents) => extents.to_pixels(page.size()),
+            None => Affine::IDENTITY,
+        }
+    }
+
+    /// The transform from the page coordinates to the physical pixels of the
+    /// window, which is applied at render time (i.e. the scene itself is not
+    /// modified).
+    pub(crate) fn view_transform(&self) -> Affine {
+        Affine::scale(self.scale_factor) * self.zoom * self.fit_transform()
+    }
+
+    /// Converts the position on the window (in logical pixels) to the
+    /// coordinates of the page, i.e. the inverse of the view transform.
+    pub(crate) fn to_page_coords(&self, point: Point) -> Point {
+        (self.zoom * self.fit_transform()).inverse() * point
+    }
+
+    /// Zooms in (factor > 1) or out (factor < 1) around the cursor.
+    pub(crate) fn zoom_at_cursor(&mut self, factor: f64) {
+        let center = self.cursor_position.unwrap_or_default().to_vec2();
+        self.zoom = Affine::translate(-center)
+            .then_scale(factor)
+            .then_translate(center)
+            * self.zoom;
+        self.request_redraw();
+    }
+
+    pub(crate) fn reset_zoom(&mut self) {
+        self.zoom = Affine::IDENTITY;
+        self.request_redraw();
+    }
+
+    pub(crate) fn on_cursor_moved(&mut self, position: Point) {
+        let last = self.cursor_position.replace(position);
+
+        let Some(drag) = &mut self.drag else {
+            return;
+        };
+        if !drag.moved && (position - drag.start).hypot() < CLICK_TOLERANCE {
+            return;
+        }
+
+        let delta = if drag.moved {
+            position - last.unwrap_or(position)
+        } else {
+            drag.moved = true;
+            position - drag.start
+        };
+        self.pan(delta);
+    }
+
+    fn pan(&mut self, delta: Vec2) {
+        self.zoom = self.zoom.then_translate(delta);
+        self.request_redraw();
+    }
+
+    pub(crate) fn on_left_button(&mut self, pressed: bool) {
+        if pressed {
+            self.drag = self.cursor_position.map(|start| Drag {
+                start,
+                moved: false,
+            });
+            return;
+        }
+
+        // Resolve the locator only on a click, as a drag is for panning
+        if let Some(Drag {
+            start,
+            moved: false,
+        }) = self.drag.take()
+        {
+            if let Some(reply) = self.locator_reply.take() {
+                // Report in the coordinates of the client
+                let point =
+                    self.page_transform(self.viewing_page()).inverse() * self.to_page_coords(start);
+                let _ = reply.send(LocatorResponse {
+                    selected: true,
+                    x: point.x,
+                    y: point.y,
+                });
+            }
+        }
+    }
+
+    /// Cancels the pending locator (e.g. by right click).
+    pub(crate) fn cancel_locator(&mut self) {
+        if let Some(reply) = self.locator_reply.take() {
+            let _ = reply.send(LocatorResponse::default());
+        }
+    }
+
+    pub(crate) fn set_locator_reply(
+        &mut self,
+        reply: tokio::sync::oneshot::Sender<LocatorResponse>,
+    ) {
+        // Without a window, no one can click
+        if !matches!(self.render_state, RenderState::Active(_)) {
+            let _ = reply.send(LocatorResponse::default());
+            return;
+        }
+
+        // If there's already a pending request, it's cancelled
+        self.cancel_locator();
+        self.locator_reply = Some(reply);
+    }
+
+    /// The transform to fit the page shown into the window.
+    fn fit_transform(&self) -> Affine {
+        let (page_width, page_height) = self.viewing_page().size();
+        let (width, height) = self.size();
+        if page_width == 0 || page_height == 0 || (page_width, page_height) == (width, height) {
+            return Affine::IDENTITY;
+        }
+
+        let scale_x = width as f64 / page_width as f64;
+        let scale_y = height as f64 / page_height as f64;
+        match self.resize_mode {
+            ResizeMode::None | ResizeMode::Notify => Affine::IDENTITY,
+            ResizeMode::Scale => Affine::scale_non_uniform(scale_x, scale_y),
+            ResizeMode::Letterbox => {
+                // Put the page in the center
+                let scale = scale_x.min(scale_y);
+                Affine::scale(scale).then_translate(
+                    (
+                        (width as f64 - page_width as f64 * scale) / 2.0,
+                        (height as f64 - page_height as f64 * scale) / 2.0,
+                    )
+                        .into(),
+                )
+            }
+        }
+    }
+
+    /// The current size of the drawing area in logical pixels.
+    pub(crate) fn size(&self) -> (u32, u32) {
+        match &self.render_state {
+            RenderState::Active(state) => (
+                (state.surface.config.width as f64 / self.scale_factor).round() as u32,
+                (state.surface.config.height as f64 / self.scale_factor).round() as u32,
+            ),
+            _ => self.config.size,
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// The retained drawing operations of a page. The scene is (re)built from
+// this, and the exports also use this as the source.
+
+use vello::{
+    kurbo::{Affine, BezPath, Circle, Line, Point},
+    peniko::{Color, Fill},
+    Scene,
+};
+
+use crate::{text::GlyphRun, FillParams, StrokeParams};
+
+#[derive(Debug, Clone)]
+pub(crate) enum DisplayItem {
+    Circle {
+        center: Point,
+        radius: f64,
+        fill_params: Option<FillParams>,
+        stroke_params: Option<StrokeParams>,
+    },
+    Line {
+        p0: Point,
+        p1: Point,
+        stroke_params: StrokeParams,
+    },
+    Polyline {
+        path: BezPath,
+        stroke_params: StrokeParams,
+    },
+    Polygon {
+        path: BezPath,
+        fill_params: Option<FillParams>,
+        stroke_params: Option<StrokeParams>,
+    },
+    Text {
+        text: String,
+        family: String,
+        size: f32,
+        color: Color,
+        // The position, the rotation and the adjustment of the text
+        transform: Affine,
+        glyph_runs: Vec<GlyphRun>,
+    },
+}
+
+impl DisplayItem {
+    pub(crate) fn draw(&self, scene: &mut Scene, transform: Affine) {
+        match self {
+            DisplayItem::Circle {
+                center,
+                radius,
+                fill_params,
+                stroke_params,
+            } => {
+                let circle = Circle::new(*center, *radius);
+                fill_and_stroke(
+                    scene,
+                    transform,
+                    &circle,
+                    fill_params.as_ref(),
+                    stroke_params.as_ref(),
+                );
+            }
+            DisplayItem::Line {
+                p0,
+                p1,
+                stroke_params,
+            } => {
+                let line = Line::new(*p0, *p1);
+                fill_and_stroke(scene, transform, &line, None, Some(stroke_params));
+            }
+            DisplayItem::Polyline {
+                path,
+                stroke_params,
+            } => {
+                fill_and_stroke(scene, transform, path, None, Some(stroke_params));
+            }
+            DisplayItem::Polygon {
+                path,
+                fill_params,
+                stroke_params,
+            } => {
+                fill_and_stroke(
+                    scene,
+                    transform,
+                    path,
+                    fill_params.as_ref(),
+                    stroke_params.as_ref(),
+                );
+            }
+            DisplayItem::Text {
+                color,
+                transform: text_transform,
+                glyph_runs,
+                ..
+            } => {
+                for run in glyph_runs {
+                    scene
+                        .draw_glyphs(&run.font)
+                        .brush(*color)
+                        .transform(transform * *text_transform)
+                        .font_size(run.font_size)
+                        .normalized_coords(&run.normalized_coords)
+                        .draw(Fill::NonZero, run.glyphs.iter().copied());
+                }
+            }
+        }
+    }
+}
+
+fn fill_and_stroke(
+    scene: &mut Scene,
+    transform: Affine,
+    shape: &impl vello::kurbo::Shape,
+    fill_params: Option<&FillParams>,
+    stroke_params: Option<&StrokeParams>,
+) {
+    if let Some(fill_params) = fill_params {
+        scene.fill(Fill::NonZero, transform, fill_params.color, None, shape);
+    }
+
+    if let Some(stroke_params) = stroke_params {
+        scene.stroke(
+            &stroke_params.stroke,
+            transform,
+            stroke_params.color,
+            None,
+            shape,
+        );
+    }
+}
+
+/// The drawing operations on a page.
+#[derive(Debug)]
+pub(crate) struct DisplayList {
+    items: Vec<DisplayItem>,
+    // The size of the device when the page started. The coordinates of the
+    // items are relative to this.
+    size: (u32, u32),
+    // This can be transparent
+    background_color: Color,
+}
+
+impl DisplayList {
+    pub(crate) fn new(size: (u32, u32), background_color: Color) -> Self {
+        Self {
+            items: Vec::new(),
+            size,
+            background_color,
+        }
+    }
+
+    pub(crate) fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    pub(crate) fn background_color(&self) -> Color {
+        self.background_color
+    }
+
+    pub(crate) fn push(&mut self, item: DisplayItem) {
+        self.items.push(item);
+    }
+
+    pub(crate) fn items(&self) -> &[DisplayItem] {
+        &self.items
+    }
+
+    /// Builds the scene from scratch.
+    pub(crate) fn build_scene(&self, scene: &mut Scene, transform: Affine) {
+        scene.reset();
+        for item in &self.items {
+            item.draw(scene, transform);
+        }
+    }
+}
@@ -0,0 +1,9 @@
+// Exporting the scene to files.
+
+mod pdf;
+mod png;
+mod svg;
+
+pub(crate) use self::pdf::pages_to_pdf;
+pub(crate) use self::png::encode_png;
+pub(crate) use self::svg::page_to_svg;
@@ -0,0 +1,553 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use pdf_writer::{
+    types::{CidFontType, FontFlags, LineCapStyle, LineJoinStyle, SystemInfo, UnicodeCmap},
+    Content, Filter, Finish, Name, Pdf, Rect, Ref, Str,
+};
+use vello::{
+    kurbo::{Affine, BezPath, Cap, Circle, Join, PathEl, Shape, Stroke},
+    peniko::Color,
+    skrifa::{
+        instance::{LocationRef, Size},
+        string::StringId,
+        FontRef, GlyphId, MetadataProvider, Tag,
+    },
+};
+
+use crate::{
+    display_list::{DisplayItem, DisplayList},
+    text::GlyphRun,
+};
+
+// The tolerance used for converting circles to Bézier curves
+const TOLERANCE: f64 = 0.1;
+
+// The text is stored as CIDs which are the same as the glyph IDs (Identity-H)
+const SYSTEM_INFO: SystemInfo = SystemInfo {
+    registry: Str(b"Adobe"),
+    ordering: Str(b"Identity"),
+    supplement: 0,
+};
+
+/// Serializes the pages into a PDF document, one PDF page per page. The pixels
+/// of the device are converted to points (1/72 inch) by the DPI of the device,
+/// so the physical size is kept. Each PDF page has the size and the background
+/// color of the page.
+pub(crate) fn pages_to_pdf(pages: &[DisplayList], dpi: f64) -> anyhow::Result<Vec<u8>> {
+    let mut writer = PdfWriter::default();
+    let points_per_pixel = (72.0 / dpi) as f32;
+
+    let contents = pages
+        .iter()
+        .map(|page| {
+            let (width, height) = page.size();
+            let media_box = Rect::new(
+                0.0,
+                0.0,
+                width as f32 * points_per_pixel,
+                height as f32 * points_per_pixel,
+            );
+            (writer.page_content(page, points_per_pixel), media_box)
+        })
+        .collect::<Vec<_>>();
+
+    writer.finish(&contents)
+}
+
+/// A font used in the document.
+struct FontEntry {
+    font: vello::peniko::Font,
+    // The name in the resource dictionary
+    name: String,
+    glyphs: BTreeSet<u16>,
+    to_unicode: BTreeMap<u16, char>,
+}
+
+#[derive(Default)]
+struct PdfWriter {
+    next_id: i32,
+    // keyed by the ID of the font data and the index in the collection
+    fonts: HashMap<(u64, u32), FontEntry>,
+    // keyed by whether it's for stroking or not, and the alpha value
+    ext_g_states: BTreeMap<(bool, u8), String>,
+}
+
+impl PdfWriter {
+    fn alloc(&mut self) -> Ref {
+        self.next_id += 1;
+        Ref::new(self.next_id)
+    }
+
+    fn page_content(&mut self, page: &DisplayList, points_per_pixel: f32) -> Vec<u8> {
+        let mut content = Content::new();
+        let (width, height) = page.size();
+        let background_color = page.background_color();
+
+        // The y axis of PDF points upward
+        content.transform([
+            points_per_pixel,
+            0.0,
+            0.0,
+            -points_per_pixel,
+            0.0,
+            height as f32 * points_per_pixel,
+        ]);
+
+        if background_color.a > 0 {
+            content.save_state();
+            self.set_fill_color(&mut content, background_color);
+            content.rect(0.0, 0.0, width as f32, height as f32);
+            content.fill_nonzero();
+            content.restore_state();
+        }
+
+        for item in page.items() {
+            content.save_state();
+            match item {
+                DisplayItem::Circle {
+                    center,
+                    radius,
+                    fill_params,
+                    stroke_params,
+                } => {
+                    let path = Circle::new(*center, *radius).to_path(TOLERANCE);
+                    self.fill_and_stroke(
+                        &mut content,
+                        &path,
+                        fill_params.as_ref().map(|p| p.color),
+                        stroke_params.as_ref().map(|p| (&p.stroke, p.color)),
+                    );
+                }
+                DisplayItem::Line {
+                    p0,
+                    p1,
+                    stroke_params,
+                } => {
+                    let mut path = BezPath::new();
+                    path.move_to(*p0);
+                    path.line_to(*p1);
+                    self.fill_and_stroke(
+                        &mut content,
+                        &path,
+                        None,
+                        Some((&stroke_params.stroke, stroke_params.color)),
+                    );
+                }
+                DisplayItem::Polyline {
+                    path,
+                    stroke_params,
+                } => self.fill_and_stroke(
+                    &mut content,
+                    path,
+                    None,
+                    Some((&stroke_params.stroke, stroke_params.color)),
+                ),
+                DisplayItem::Polygon {
+                    path,
+                    fill_params,
+                    stroke_params,
+                } => self.fill_and_stroke(
+                    &mut content,
+                    path,
+                    fill_params.as_ref().map(|p| p.color),
+                    stroke_params.as_ref().map(|p| (&p.stroke, p.color)),
+                ),
+                DisplayItem::Text {
+                    text,
+                    color,
+                    transform,
+                    glyph_runs,
+                    ..
+                } => {
+                    self.set_fill_color(&mut content, *color);
+                    content.begin_text();
+                    for run in glyph_runs {
+                        self.show_glyph_run(&mut content, text, *transform, run);
+                    }
+                    content.end_text();
+                }
+            }
+            content.restore_state();
+        }
+
+        content.finish()
+    }
+
+    fn fill_and_stroke(
+        &mut self,
+        content: &mut Content,
+        path: &BezPath,
+        fill_color: Option<Color>,
+        stroke: Option<(&Stroke, Color)>,
+    ) {
+        if let Some(color) = fill_color {
+            self.set_fill_color(content, color);
+            write_path(content, path);
+            content.fill_nonzero();
+        }
+
+        if let Some((stroke, color)) = stroke {
+            self.set_stroke_color(content, color);
+            set_stroke_style(content, stroke);
+            write_path(content, path);
+            content.stroke();
+        }
+    }
+
+    fn set_fill_color(&mut self, content: &mut Content, color: Color) {
+        content.set_fill_rgb(
+            color.r as f32 / 255.0,
+            color.g as f32 / 255.0,
+            color.b as f32 / 255.0,
+        );
+        if color.a < 255 {
+            let name = self.ext_g_state_name(false, color.a);
+            content.set_parameters(Name(name.as_bytes()));
+        }
+    }
+
+    fn set_stroke_color(&mut self, content: &mut Content, color: Color) {
+        content.set_stroke_rgb(
+            color.r as f32 / 255.0,
+            color.g as f32 / 255.0,
+            color.b as f32 / 255.0,
+        );
+        if color.a < 255 {
+            let name = self.ext_g_state_name(true, color.a);
+            content.set_parameters(Name(name.as_bytes()));
+        }
+    }
+
+    fn ext_g_state_name(&mut self, stroking: bool, alpha: u8) -> String {
+        let n = self.ext_g_states.len();
+        self.ext_g_states
+            .entry((stroking, alpha))
+            .or_insert_with(|| format!("Gs{n}"))
+            .clone()
+    }
+
+    fn show_glyph_run(
+        &mut self,
+        content: &mut Content,
+        text: &str,
+        transform: Affine,
+        run: &GlyphRun,
+    ) {
+        let n = self.fonts.len();
+        let entry = self
+            .fonts
+            .entry((run.font.data.id(), run.font.index))
+            .or_insert_with(|| FontEntry {
+                font: run.font.clone(),
+                name: format!("F{n}"),
+                glyphs: BTreeSet::new(),
+                to_unicode: BTreeMap::new(),
+            });
+
+        // The glyphs don't know which characters they come from, so this is
+        // just the best effort via the font's cmap table. The ligatures are
+        // not copyable.
+        if let Ok(font_ref) = FontRef::from_index(run.font.data.data(), run.font.index) {
+            let charmap = font_ref.charmap();
+            for c in text.chars() {
+                if let Some(id) = charmap
+                    .map(c)
+                    .and_then(|id| u16::try_from(id.to_u32()).ok())
+                {
+                    entry.to_unicode.entry(id).or_insert(c);
+                }
+            }
+        }
+
+        // TODO: the variations (normalized_coords) are ignored and the
+        // default instance is used.
+        content.set_font(Name(entry.name.as_bytes()), run.font_size);
+        for glyph in &run.glyphs {
+            // The glyph IDs can be represented by CIDs only when they fit in 2 bytes
+            let Ok(id) = u16::try_from(glyph.id) else {
+                continue;
+            };
+            entry.glyphs.insert(id);
+
+            let matrix =
+                transform * Affine::translate((glyph.x as f64, glyph.y as f64)) * Affine::FLIP_Y;
+            content.set_text_matrix(to_matrix(matrix));
+            content.show(Str(&id.to_be_bytes()));
+        }
+    }
+
+    fn finish(mut self, contents: &[(Vec<u8>, Rect)]) -> anyhow::Result<Vec<u8>> {
+        let mut pdf = Pdf::new();
+
+        let catalog_id = self.alloc();
+        let page_tree_id = self.alloc();
+        let page_ids = contents.iter().map(|_| self.alloc()).collect::<Vec<_>>();
+
+        for ((content, media_box), &page_id) in contents.iter().zip(&page_ids) {
+            let content_id = self.alloc();
+            pdf.stream(content_id, &compress(content))
+                .filter(Filter::FlateDecode);
+
+            let mut page = pdf.page(page_id);
+            page.parent(page_tree_id)
+                .media_box(*media_box)
+                .contents(content_id);
+            page.finish();
+        }
+
+        let ext_g_states = std::mem::take(&mut self.ext_g_states)
+            .into_iter()
+            .map(|((stroking, alpha), name)| {
+                let id = self.alloc();
+                let mut ext_g_state = pdf.ext_graphics(id);
+                if stroking {
+                    ext_g_state.stroking_alpha(alpha as f32 / 255.0);
+                } else {
+                    ext_g_state.non_stroking_alpha(alpha as f32 / 255.0);
+                }
+                ext_g_state.finish();
+                (name, id)
+            })
+            .collect::<Vec<_>>();
+
+        let fonts = std::mem::take(&mut self.fonts)
+            .into_values()
+            .map(|entry| {
+                let id = self.write_font(&mut pdf, &entry)?;
+                Ok((entry.name, id))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // The resources are shared among all the pages
+        let mut page_tree = pdf.pages(page_tree_id);
+        page_tree
+            .kids(page_ids.iter().copied())
+            .count(page_ids.len() as i32);
+        let mut resources = page_tree.resources();
+        let mut font_dict = resources.fonts();
+        for (name, id) in &fonts {
+            font_dict.pair(Name(name.as_bytes()), *id);
+        }
+        font_dict.finish();
+        let mut ext_g_state_dict = resources.ext_g_states();
+        for (name, id) in &ext_g_states {
+            ext_g_state_dict.pair(Name(name.as_bytes()), *id);
+        }
+        ext_g_state_dict.finish();
+        resources.finish();
+        page_tree.finish();
+
+        pdf.catalog(catalog_id).pages(page_tree_id);
+
+        Ok(pdf.finish())
+    }
+
+    /// Embeds the subset of the font as a Type0 font and returns its ID.
+    fn write_font(&mut self, pdf: &mut Pdf, entry: &FontEntry) -> anyhow::Result<Ref> {
+        let type0_id = self.alloc();
+        let cid_id = self.alloc();
+        let descriptor_id = self.alloc();
+        let font_file_id = self.alloc();
+        let to_unicode_id = self.alloc();
+
+        let data = entry.font.data.data();
+        let font_ref = FontRef::from_index(data, entry.font.index)?;
+        let metrics = font_ref.metrics(Size::unscaled(), LocationRef::default());
+        let glyph_metrics = font_ref.glyph_metrics(Size::unscaled(), LocationRef::default());
+        // The glyph space of PDF is 1000 units per em
+        let to_pdf_units = 1000.0 / metrics.units_per_em as f32;
+
+        let postscript_name = font_ref
+            .localized_strings(StringId::POSTSCRIPT_NAME)
+            .english_or_first()
+            .map(|s| {
+                s.chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+                    .collect()
+            })
+            .unwrap_or_else(|| "Unknown".to_string());
+        let base_font = format!("{}+{postscript_name}", subset_tag(&entry.glyphs));
+
+        let glyphs = entry.glyphs.iter().copied().collect::<Vec<_>>();
+        let subset = subsetter::subset(data, entry.font.index, subsetter::Profile::pdf(&glyphs))?;
+        let is_cff = font_ref.table_data(Tag::new(b"CFF ")).is_some()
+            || font_ref.table_data(Tag::new(b"CFF2")).is_some();
+
+        pdf.type0_font(type0_id)
+            .base_font(Name(base_font.as_bytes()))
+            .encoding_predefined(Name(b"Identity-H"))
+            .descendant_font(cid_id)
+            .to_unicode(to_unicode_id);
+
+        let mut cid = pdf.cid_font(cid_id);
+        cid.subtype(if is_cff {
+            CidFontType::Type0
+        } else {
+            CidFontType::Type2
+        })
+        .base_font(Name(base_font.as_bytes()))
+        .system_info(SYSTEM_INFO)
+        .font_descriptor(descriptor_id)
+        .default_width(0.0);
+        if !is_cff {
+            cid.cid_to_gid_map_predefined(Name(b"Identity"));
+        }
+        let mut widths = cid.widths();
+        for &id in &glyphs {
+            let width = glyph_metrics
+                .advance_width(GlyphId::new(id as u32))
+                .unwrap_or(0.0);
+            widths.consecutive(id, [width * to_pdf_units]);
+        }
+        widths.finish();
+        cid.finish();
+
+        let bbox = metrics
+            .bounds
+            .map(|b| {
+                Rect::new(
+                    b.x_min * to_pdf_units,
+                    b.y_min * to_pdf_units,
+                    b.x_max * to_pdf_units,
+                    b.y_max * to_pdf_units,
+                )
+            })
+            .unwrap_or(Rect::new(0.0, 0.0, 0.0, 0.0));
+        let mut flags = FontFlags::SYMBOLIC;
+        if metrics.is_monospace {
+            flags |= FontFlags::FIXED_PITCH;
+        }
+        if metrics.italic_angle != 0.0 {
+            flags |= FontFlags::ITALIC;
+        }
+
+        let mut descriptor = pdf.font_descriptor(descriptor_id);
+        descriptor
+            .name(Name(base_font.as_bytes()))
+            .flags(flags)
+            .bbox(bbox)
+            .italic_angle(metrics.italic_angle)
+            .ascent(metrics.ascent * to_pdf_units)
+            .descent(metrics.descent * to_pdf_units)
+            .cap_height(metrics.cap_height.unwrap_or(metrics.ascent) * to_pdf_units)
+            // There's no way to know this, so use a typical value
+            .stem_v(80.0);
+        if is_cff {
+            descriptor.font_file3(font_file_id);
+        } else {
+            descriptor.font_file2(font_file_id);
+        }
+        descriptor.finish();
+
+        let subset = compress(&subset);
+        let mut font_file = pdf.stream(font_file_id, &subset);
+        font_file.filter(Filter::FlateDecode);
+        if is_cff {
+            font_file.pair(Name(b"Subtype"), Name(b"OpenType"));
+        }
+        font_file.finish();
+
+        let mut cmap = UnicodeCmap::new(Name(b"Custom"), SYSTEM_INFO);
+        for (&id, &c) in &entry.to_unicode {
+            cmap.pair(id, c);
+        }
+        pdf.cmap(to_unicode_id, &cmap.finish());
+
+        Ok(type0_id)
+    }
+}
+
+fn set_stroke_style(content: &mut Content, stroke: &Stroke) {
+    content.set_line_width(stroke.width as f32);
+    // R uses the same cap for both ends
+    content.set_line_cap(match stroke.start_cap {
+        Cap::Butt => LineCapStyle::ButtCap,
+        Cap::Square => LineCapStyle::ProjectingSquareCap,
+        Cap::Round => LineCapStyle::RoundCap,
+    });
+    content.set_line_join(match stroke.join {
+        Join::Bevel => LineJoinStyle::BevelJoin,
+        Join::Miter => LineJoinStyle::MiterJoin,
+        Join::Round => LineJoinStyle::RoundJoin,
+    });
+    content.set_miter_limit(stroke.miter_limit as f32);
+    if !stroke.dash_pattern.is_empty() {
+        content.set_dash_pattern(
+            stroke.dash_pattern.iter().map(|d| *d as f32),
+            stroke.dash_offset as f32,
+        );
+    }
+}
+
+fn write_path(content: &mut Content, path: &BezPath) {
+    let mut start = vello::kurbo::Point::ZERO;
+    let mut last = start;
+    for el in path.elements() {
+        match *el {
+            PathEl::MoveTo(p) => {
+                content.move_to(p.x as f32, p.y as f32);
+                start = p;
+                last = p;
+            }
+            PathEl::LineTo(p) => {
+                content.line_to(p.x as f32, p.y as f32);
+                last = p;
+            }
+            // PDF doesn't have quadratic Bézier curves, so convert it to cubic
+            PathEl::QuadTo(p1, p2) => {
+                let c1 = last + (p1 - last) * (2.0 / 3.0);
+                let c2 = p2 + (p1 - p2) * (2.0 / 3.0);
+                content.cubic_to(
+                    c1.x as f32,
+                    c1.y as f32,
+                    c2.x as f32,
+                    c2.y as f32,
+                    p2.x as f32,
+                    p2.y as f32,
+                );
+                last = p2;
+            }
+            PathEl::CurveTo(p1, p2, p3) => {
+                content.cubic_to(
+                    p1.x as f32,
+                    p1.y as f32,
+                    p2.x as f32,
+                    p2.y as f32,
+                    p3.x as f32,
+                    p3.y as f32,
+                );
+                last = p3;
+            }
+            PathEl::ClosePath => {
+                content.close_path();
+                last = start;
+            }
+        }
+    }
+}
+
+fn to_matrix(transform: Affine) -> [f32; 6] {
+    transform.as_coeffs().map(|x| x as f32)
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    miniz_oxide::deflate::compress_to_vec_zlib(data, 6)
+}
+
+/// The tag of a subset font is six uppercase letters, which should be unique
+/// per subset.
+fn subset_tag(glyphs: &BTreeSet<u16>) -> String {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    glyphs.hash(&mut hasher);
+    let mut hash = Hasher::finish(&hasher);
+
+    (0..6)
+        .map(|_| {
+            let c = (b'A' + (hash % 26) as u8) as char;
+            hash /= 26;
+            c
+        })
+        .collect()
+}
@@ -0,0 +1,26 @@
+/// Encodes RGBA8 pixels into PNG. The DPI is recorded in the pHYs chunk.
+pub(crate) fn encode_png(
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    dpi: f64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+
+    let mut encoder = ::png::Encoder::new(&mut data, width, height);
+    encoder.set_color(::png::ColorType::Rgba);
+    encoder.set_depth(::png::BitDepth::Eight);
+    // pHYs chunk doesn't support inch, so convert it to meter
+    let pixels_per_meter = (dpi / 0.0254).round() as u32;
+    encoder.set_pixel_dims(Some(::png::PixelDimensions {
+        xppu: pixels_per_meter,
+        yppu: pixels_per_meter,
+        unit: ::png::Unit::Meter,
+    }));
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    writer.finish()?;
+
+    Ok(data)
+}
@@ -0,0 +1,234 @@
+use std::fmt::Write;
+
+use vello::{
+    kurbo::{Affine, Cap, Join, Stroke},
+    peniko::Color,
+};
+
+use crate::display_list::{DisplayItem, DisplayList};
+
+/// Serializes the page into a standalone SVG document of the page's size. The
+/// size is converted to CSS pixels (1/96 inch) by the DPI of the device, while
+/// the coordinates inside are the device's pixels.
+pub(crate) fn page_to_svg(display_list: &DisplayList, text_as_path: bool, dpi: f64) -> String {
+    let mut out = String::new();
+    write_svg(&mut out, display_list, text_as_path, dpi).expect("writing to String never fails");
+    out
+}
+
+fn write_svg(
+    out: &mut String,
+    display_list: &DisplayList,
+    text_as_path: bool,
+    dpi: f64,
+) -> std::fmt::Result {
+    let (width, height) = display_list.size();
+    let background_color = display_list.background_color();
+    let css_width = width as f64 * 96.0 / dpi;
+    let css_height = height as f64 * 96.0 / dpi;
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{css_width}" height="{css_height}" viewBox="0 0 {width} {height}">"#
+    )?;
+
+    if background_color.a > 0 {
+        writeln!(
+            out,
+            r#"<rect width="{width}" height="{height}"{}/>"#,
+            Fill(Some(background_color))
+        )?;
+    }
+
+    for item in display_list.items() {
+        match item {
+            DisplayItem::Circle {
+                center,
+                radius,
+                fill_params,
+                stroke_params,
+            } => writeln!(
+                out,
+                r#"<circle cx="{}" cy="{}" r="{radius}"{}{}/>"#,
+                center.x,
+                center.y,
+                Fill(fill_params.as_ref().map(|p| p.color)),
+                StrokeAttrs(stroke_params.as_ref().map(|p| (&p.stroke, p.color))),
+            )?,
+            DisplayItem::Line {
+                p0,
+                p1,
+                stroke_params,
+            } => writeln!(
+                out,
+                r#"<line x1="{}" y1="{}" x2="{}" y2="{}"{}/>"#,
+                p0.x,
+                p0.y,
+                p1.x,
+                p1.y,
+                StrokeAttrs(Some((&stroke_params.stroke, stroke_params.color))),
+            )?,
+            DisplayItem::Polyline {
+                path,
+                stroke_params,
+            } => writeln!(
+                out,
+                r#"<path d="{}"{}{}/>"#,
+                path.to_svg(),
+                Fill(None),
+                StrokeAttrs(Some((&stroke_params.stroke, stroke_params.color))),
+            )?,
+            DisplayItem::Polygon {
+                path,
+                fill_params,
+                stroke_params,
+            } => writeln!(
+                out,
+                r#"<path d="{}"{}{}/>"#,
+                path.to_svg(),
+                Fill(fill_params.as_ref().map(|p| p.color)),
+                StrokeAttrs(stroke_params.as_ref().map(|p| (&p.stroke, p.color))),
+            )?,
+            DisplayItem::Text {
+                text,
+                family,
+                size,
+                color,
+                transform,
+                glyph_runs,
+            } => {
+                if text_as_path {
+                    for run in glyph_runs {
+                        writeln!(
+                            out,
+                            r#"<path d="{}"{}/>"#,
+                            run.to_path(*transform).to_svg(),
+                            Fill(Some(*color))
+                        )?;
+                    }
+                } else {
+                    // The glyphs are positioned relative to the first one
+                    let (x, y) = glyph_runs
+                        .first()
+                        .and_then(|run| run.glyphs.first())
+                        .map(|g| (g.x, g.y))
+                        .unwrap_or_default();
+
+                    writeln!(
+                        out,
+                        r#"<text x="{x}" y="{y}" transform="{}" font-family="{}" font-size="{size}"{}>{}</text>"#,
+                        Matrix(*transform),
+                        Escaped(family),
+                        Fill(Some(*color)),
+                        Escaped(text),
+                    )?;
+                }
+            }
+        }
+    }
+
+    writeln!(out, "</svg>")
+}
+
+struct Matrix(Affine);
+
+impl std::fmt::Display for Matrix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0.as_coeffs();
+        write!(f, "matrix({a} {b} {c} {d} {e} {g})")
+    }
+}
+
+struct Escaped<'a>(&'a str);
+
+impl std::fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&apos;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Rgb(Color);
+
+impl std::fmt::Display for Rgb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.0.r, self.0.g, self.0.b)
+    }
+}
+
+/// fill and fill-opacity attributes
+struct Fill(Option<Color>);
+
+impl std::fmt::Display for Fill {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(color) => {
+                write!(f, r#" fill="{}""#, Rgb(color))?;
+                if color.a < 255 {
+                    write!(f, r#" fill-opacity="{}""#, color.a as f64 / 255.0)?;
+                }
+                Ok(())
+            }
+            None => write!(f, r#" fill="none""#),
+        }
+    }
+}
+
+/// stroke and stroke-* attributes
+struct StrokeAttrs<'a>(Option<(&'a Stroke, Color)>);
+
+impl std::fmt::Display for StrokeAttrs<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some((stroke, color)) = self.0 else {
+            return Ok(());
+        };
+
+        write!(f, r#" stroke="{}""#, Rgb(color))?;
+        if color.a < 255 {
+            write!(f, r#" stroke-opacity="{}""#, color.a as f64 / 255.0)?;
+        }
+        write!(f, r#" stroke-width="{}""#, stroke.width)?;
+
+        // R uses the same cap for both ends
+        let cap = match stroke.start_cap {
+            Cap::Butt => "butt",
+            Cap::Square => "square",
+            Cap::Round => "round",
+        };
+        let join = match stroke.join {
+            Join::Bevel => "bevel",
+            Join::Miter => "miter",
+            Join::Round => "round",
+        };
+        write!(
+            f,
+            r#" stroke-linecap="{cap}" stroke-linejoin="{join}" stroke-miterlimit="{}""#,
+            stroke.miter_limit
+        )?;
+
+        if !stroke.dash_pattern.is_empty() {
+            let dashes = stroke
+                .dash_pattern
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            write!(
+                f,
+                r#" stroke-dasharray="{dashes}" stroke-dashoffset="{}""#,
+                stroke.dash_offset
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+// The wgpu instance and the devices for the windows. This is based on vello's
+// RenderContext (vello/src/util.rs), but the adapter is chosen by the
+// AdapterPreference instead of wgpu's environment variables.
+
+use vello::wgpu;
+
+use crate::render_options::{self, AdapterPreference};
+
+pub(crate) struct GpuDevice {
+    pub(crate) adapter: wgpu::Adapter,
+    pub(crate) device: wgpu::Device,
+    pub(crate) queue: wgpu::Queue,
+    // The preference the adapter was chosen by
+    pub(crate) preference: AdapterPreference,
+}
+
+impl GpuDevice {
+    pub(crate) async fn new(
+        adapter: wgpu::Adapter,
+        preference: AdapterPreference,
+    ) -> anyhow::Result<Self> {
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    // vello uses this if available
+                    required_features: adapter.features() & wgpu::Features::CLEAR_TEXTURE,
+                    required_limits: wgpu::Limits::default(),
+                    memory_hints: wgpu::MemoryHints::default(),
+                },
+                None,
+            )
+            .await?;
+
+        Ok(Self {
+            adapter,
+            device,
+            queue,
+            preference,
+        })
+    }
+}
+
+pub(crate) struct GpuSurface<'s> {
+    pub(crate) surface: wgpu::Surface<'s>,
+    pub(crate) config: wgpu::SurfaceConfiguration,
+    // The index of GpuContext::devices
+    pub(crate) dev_id: usize,
+    pub(crate) format: wgpu::TextureFormat,
+}
+
+pub(crate) struct GpuContext {
+    pub(crate) instance: wgpu::Instance,
+    pub(crate) devices: Vec<GpuDevice>,
+    pub(crate) adapter_preference: AdapterPreference,
+}
+
+impl GpuContext {
+    pub(crate) fn new(adapter_preference: AdapterPreference) -> Self {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::util::backend_bits_from_env().unwrap_or_default(),
+            dx12_shader_compiler: wgpu::util::dx12_shader_compiler_from_env().unwrap_or_default(),
+            ..Default::default()
+        });
+
+        Self {
+            instance,
+            devices: Vec::new(),
+            adapter_preference,
+        }
+    }
+
+    /// Creates a surface for the window. The device is chosen by the given
+    /// preference, which is usually adapter_preference but can differ for the
+    /// fallback of a particular window.
+    pub(crate) async fn create_surface<'w>(
+        &mut self,
+        window: impl Into<wgpu::SurfaceTarget<'w>>,
+        width: u32,
+        height: u32,
+        present_mode: wgpu::PresentMode,
+        preference: &AdapterPreference,
+    ) -> anyhow::Result<GpuSurface<'w>> {
+        let surface = self.instance.create_surface(window)?;
+        let dev_id = self.device(&surface, preference).await?;
+
+        let capabilities = surface.get_capabilities(&self.devices[dev_id].adapter);
+        let format = capabilities
+            .formats
+            .into_iter()
+            .find(|format| {
+                matches!(
+                    format,
+                    wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Bgra8Unorm
+                )
+            })
+            .ok_or_else(|| anyhow::anyhow!("the surface supports no format vello can render"))?;
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format,
+            width,
+            height,
+            present_mode,
+            desired_maximum_frame_latency: 2,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        };
+        let surface = GpuSurface {
+            surface,
+            config,
+            dev_id,
+            format,
+        };
+        self.configure_surface(&surface);
+        Ok(surface)
+    }
+
+    pub(crate) fn resize_surface(&self, surface: &mut GpuSurface, width: u32, height: u32) {
+        surface.config.width = width;
+        surface.config.height = height;
+        self.configure_surface(surface);
+    }
+
+    pub(crate) fn set_present_mode(
+        &self,
+        surface: &mut GpuSurface,
+        present_mode: wgpu::PresentMode,
+    ) {
+        surface.config.present_mode = present_mode;
+        self.configure_surface(surface);
+    }
+
+    fn configure_surface(&self, surface: &GpuSurface) {
+        let device = &self.devices[surface.dev_id].device;
+        surface.surface.configure(device, &surface.config);
+    }
+
+    /// Returns the index of a device chosen by the preference that can present
+    /// to the surface, creating one if none of the existing devices can.
+    async fn device(
+        &mut self,
+        surface: &wgpu::Surface<'_>,
+        preference: &AdapterPreference,
+    ) -> anyhow::Result<usize> {
+        if let Some(dev_id) = self.devices.iter().position(|device| {
+            device.preference == *preference && device.adapter.is_surface_supported(surface)
+        }) {
+            return Ok(dev_id);
+        }
+
+        let adapter =
+            render_options::select_adapter(&self.instance, preference, Some(surface)).await?;
+        self.devices
+            .push(GpuDevice::new(adapter, preference.clone()).await?);
+        Ok(self.devices.len() - 1)
+    }
+}
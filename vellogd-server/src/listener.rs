@@ -0,0 +1,84 @@
+// Where the gRPC server listens. Besides TCP, a Unix domain socket is
+// available on Unix so that a server is private to the user who runs it.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub(crate) enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// Binds the TCP address. If the port is 0, a free port is chosen by the
+    /// OS, so multiple servers can run at the same time.
+    pub(crate) async fn bind_tcp(addr: SocketAddr) -> std::io::Result<Self> {
+        Ok(Listener::Tcp(tokio::net::TcpListener::bind(addr).await?))
+    }
+
+    /// The address for the clients to connect to, e.g. "http://[::1]:50051".
+    pub(crate) fn client_address(&self) -> std::io::Result<String> {
+        match self {
+            Listener::Tcp(listener) => {
+                let mut addr = listener.local_addr()?;
+                // The clients can't connect to the unspecified address (e.g. 0.0.0.0)
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                Ok(format!("http://{addr}"))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr
+                    .as_pathname()
+                    .ok_or_else(|| std::io::Error::other("the Unix domain socket has no path"))?;
+                // The path needs to be absolute for the URI
+                Ok(format!("unix://{}", std::path::absolute(path)?.display()))
+            }
+        }
+    }
+}
+
+/// The socket file of the Unix domain socket, which is removed on drop like
+/// the discovery file.
+#[cfg(unix)]
+pub(crate) struct SocketFile {
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Binds the Unix domain socket that only the owner can connect to.
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &std::path::Path) -> std::io::Result<(Listener, SocketFile)> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // The socket file left by a crashed server prevents binding, so remove it
+    // unless another server is still listening on it.
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if is_socket && std::os::unix::net::UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+
+    // Create the socket with 0600 from the start. Changing the permissions
+    // after bind would leave a moment when other users can connect. The umask
+    // is process-wide, but this runs before the server starts to create files.
+    let old_umask = unsafe { libc::umask(0o177) };
+    let result = tokio::net::UnixListener::bind(path);
+    unsafe { libc::umask(old_umask) };
+    let listener = result?;
+
+    let socket_file = SocketFile {
+        path: path.to_path_buf(),
+    };
+    Ok((Listener::Unix(listener), socket_file))
+}
@@ -3,59 +3,218 @@
 // - the example code on linbender/vello (examples/simple/main.rs).
 // - the example code on linbender/parley (examples/vello_editor/src/main.rs).
 
+mod device;
+mod display_list;
+mod export;
+mod gpu;
+mod listener;
+mod offscreen;
+mod render_options;
+mod text;
 mod utils;
-
-use std::{num::NonZeroUsize, sync::Arc};
-
-use utils::u32_to_color;
-use vello::{
-    peniko::Color,
-    util::{RenderContext, RenderSurface},
-    AaConfig, Renderer, RendererOptions, Scene,
+mod validation;
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
+
+use clap::Parser;
+use device::{DeviceConfig, DeviceState, Extents};
+use display_list::DisplayItem;
+use gpu::{GpuContext, GpuSurface};
+use listener::Listener;
+use offscreen::OffscreenDevice;
+use render_options::{AdapterPreference, Antialiasing, RenderOptions};
+use validation::ValidationError;
+use vello::{kurbo::Affine, peniko::Color, Renderer, Scene};
 use winit::{
     application::ApplicationHandler,
-    event::WindowEvent,
-    event_loop::{EventLoop, EventLoopProxy},
-    window::Window,
+    event::{ElementState, MouseButton, MouseScrollDelta, WindowEvent},
+    event_loop::{ControlFlow, EventLoop, EventLoopProxy},
+    keyboard::{Key, NamedKey},
+    window::{Window, WindowId},
 };
 
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
 use tonic::{transport::Server, Request, Response, Status};
 
 use vellogd_protocol::graphics_device_server::{GraphicsDevice, GraphicsDeviceServer};
 use vellogd_protocol::*;
 
+/// The destination of the UserEvents. In headless mode, there's no winit's
+/// event loop, so the events are sent via a channel instead.
+#[derive(Debug, Clone)]
+enum EventSender {
+    EventLoop(EventLoopProxy<DeviceEvent>),
+    Headless(std::sync::mpsc::Sender<DeviceEvent>),
+}
+
+impl EventSender {
+    // Status is large, but this is what the handlers return anyway
+    #[allow(clippy::result_large_err)]
+    fn send_event(&self, device_id: u32, event: UserEvent) -> Result<(), Status> {
+        let event = DeviceEvent { device_id, event };
+        match self {
+            EventSender::EventLoop(proxy) => proxy
+                .send_event(event)
+                .map_err(|e| Status::from_error(Box::new(e))),
+            EventSender::Headless(sender) => sender
+                .send(event)
+                .map_err(|e| Status::from_error(Box::new(e))),
+        }
+    }
+}
+
+// The default size of the window in logical pixels
+const DEFAULT_WIDTH: u32 = 600;
+const DEFAULT_HEIGHT: u32 = 600;
+
+// The maximum number of pages kept in the history. The oldest page is
+// discarded when this is exceeded.
+const MAX_PAGES: usize = 100;
+
+const DEFAULT_TITLE: &str = "vellogd";
+
+// The default resolution of the devices, i.e. 1 pixel is 1 point (the same as
+// R's png() device). This is in logical pixels; the scale factor of the window
+// is applied separately.
+const BASE_DPI: f64 = 72.0;
+
+// The maximum size of a page in logical pixels. This is also the largest
+// texture that wgpu's default limits allow.
+const MAX_PAGE_SIZE: u32 = 8192;
+
+// The number of notifications buffered for each WatchEvents stream
+const NOTIFICATION_CAPACITY: usize = 64;
+
+// The longest time a change waits to be rendered while the client keeps
+// drawing. Without this, the redraw delay would postpone the redraw until the
+// client stops. A longer --redraw-delay takes precedence.
+const MAX_REDRAW_WAIT: Duration = Duration::from_millis(100);
+
 #[derive(Debug)]
 struct VelloGraphicsDevice {
-    event_loop_proxy: EventLoopProxy<UserEvent>,
+    event_sender: EventSender,
+    // Device 0 is created at startup
+    next_device_id: AtomicU32,
+    notifier: broadcast::Sender<DeviceNotification>,
+    // Used to convert the line widths in physical units to pixels
+    dpi: f64,
+    open_devices: OpenDevices,
 }
 
+// The IDs of the devices that are open, shared by the gRPC server and the
+// event loop so that the requests to unknown devices fail immediately. The
+// event loop removes the device when its window is closed by the user.
+type OpenDevices = Arc<Mutex<HashSet<u32>>>;
+
 impl VelloGraphicsDevice {
-    fn new(event_loop_proxy: EventLoopProxy<UserEvent>) -> Self {
-        Self { event_loop_proxy }
+    fn new(
+        event_sender: EventSender,
+        notifier: broadcast::Sender<DeviceNotification>,
+        dpi: f64,
+        open_devices: OpenDevices,
+    ) -> Self {
+        Self {
+            event_sender,
+            next_device_id: AtomicU32::new(1),
+            notifier,
+            dpi,
+            open_devices,
+        }
+    }
+
+    /// Sends the event to the device. Fails with NotFound if the device is
+    /// not open.
+    #[allow(clippy::result_large_err)]
+    fn send_device_event(&self, device_id: u32, event: UserEvent) -> Result<(), Status> {
+        if !self.open_devices.lock().unwrap().contains(&device_id) {
+            return Err(Status::not_found(format!("device {device_id}")));
+        }
+        self.event_sender.send_event(device_id, event)
     }
 }
 
 #[tonic::async_trait]
 impl GraphicsDevice for VelloGraphicsDevice {
-    async fn close_window(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        println!("{:?}", request);
+    type WatchEventsStream =
+        Pin<Box<dyn Stream<Item = Result<DeviceNotification, Status>> + Send + 'static>>;
 
-        self.event_loop_proxy
-            .send_event(UserEvent::CloseWindow)
-            .map_err(|e| Status::from_error(Box::new(e)))?;
+    async fn open_device(
+        &self,
+        request: Request<OpenDeviceRequest>,
+    ) -> Result<Response<OpenDeviceResponse>, Status> {
+        log::debug!("{:?}", request);
+
+        let device_id = self.next_device_id.fetch_add(1, Ordering::Relaxed);
+        self.open_devices.lock().unwrap().insert(device_id);
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.event_sender
+            .send_event(device_id, UserEvent::OpenDevice { reply })?;
+
+        // The event loop drops the device on failure
+        receiver
+            .await
+            .map_err(|e| Status::from_error(Box::new(e)))?
+            .map_err(|e| Status::internal(format!("failed to open device: {e}")))?;
+
+        let reply = OpenDeviceResponse { device_id };
+        Ok(Response::new(reply))
+    }
+
+    async fn close_window(
+        &self,
+        request: Request<CloseWindowRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        log::debug!("{:?}", request);
+
+        let CloseWindowRequest { device_id } = request.into_inner();
+
+        self.send_device_event(device_id, UserEvent::CloseWindow)?;
+        self.open_devices.lock().unwrap().remove(&device_id);
 
         let reply = Empty {};
 
         Ok(Response::new(reply))
     }
 
-    async fn new_page(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        println!("{:?}", request);
+    async fn new_page(&self, request: Request<NewPageRequest>) -> Result<Response<Empty>, Status> {
+        log::debug!("{:?}", request);
 
-        self.event_loop_proxy
-            .send_event(UserEvent::NewPage)
-            .map_err(|e| Status::from_error(Box::new(e)))?;
+        let NewPageRequest {
+            device_id,
+            background_color,
+            width,
+            height,
+            background_color_rgba,
+        } = request.into_inner();
+
+        let width = validation::at_most("width", width, MAX_PAGE_SIZE)?;
+        let height = validation::at_most("height", height, MAX_PAGE_SIZE)?;
+        let size = if width > 0 && height > 0 {
+            Some((width, height))
+        } else {
+            None
+        };
+        let background_color =
+            utils::optional_color_from_request(background_color, background_color_rgba)
+                .map_err(|e| e.in_field("background_color_rgba"))?;
+
+        self.send_device_event(
+            device_id,
+            UserEvent::NewPage {
+                size,
+                background_color,
+            },
+        )?;
 
         let reply = Empty {};
 
@@ -66,7 +225,7 @@ impl GraphicsDevice for VelloGraphicsDevice {
         &self,
         request: Request<DrawCircleRequest>,
     ) -> Result<Response<Empty>, Status> {
-        println!("{:?}", request);
+        log::debug!("{:?}", request);
 
         let DrawCircleRequest {
             cx,
@@ -74,19 +233,31 @@ impl GraphicsDevice for VelloGraphicsDevice {
             radius,
             fill_color,
             stroke_params,
+            device_id,
+            fill_color_rgba,
         } = request.into_inner();
 
-        let fill_params = fill_color.map(FillParams::from_request);
-        let stroke_params = stroke_params.map(StrokeParams::from_request);
-
-        self.event_loop_proxy
-            .send_event(UserEvent::DrawCircle {
+        let cx = validation::finite("cx", cx)?;
+        let cy = validation::finite("cy", cy)?;
+        let radius = validation::non_negative("radius", radius)?;
+        let fill_params = FillParams::from_request(fill_color, fill_color_rgba)
+            .map_err(|e| e.in_field("fill_color_rgba"))?;
+        let stroke_params = stroke_params
+            .map(|params| StrokeParams::from_request(params, self.dpi))
+            .transpose()
+            .map_err(|e| e.in_field("stroke_params"))?
+            // LTY_BLANK
+            .flatten();
+
+        self.send_device_event(
+            device_id,
+            UserEvent::DrawCircle {
                 center: vello::kurbo::Point::new(cx, cy),
                 radius,
                 fill_params,
                 stroke_params,
-            })
-            .map_err(|e| Status::from_error(Box::new(e)))?;
+            },
+        )?;
         let reply = Empty {};
         Ok(Response::new(reply))
     }
@@ -95,7 +266,7 @@ impl GraphicsDevice for VelloGraphicsDevice {
         &self,
         request: Request<DrawLineRequest>,
     ) -> Result<Response<Empty>, Status> {
-        println!("{:?}", request);
+        log::debug!("{:?}", request);
 
         let DrawLineRequest {
             x0,
@@ -103,24 +274,30 @@ impl GraphicsDevice for VelloGraphicsDevice {
             x1,
             y1,
             stroke_params,
+            device_id,
         } = request.into_inner();
 
-        let stroke_params = stroke_params.ok_or_else(|| {
-            Status::new(
-                tonic::Code::InvalidArgument,
-                "stroke_params must be specified",
-            )
-        })?;
-
-        let stroke_params = StrokeParams::from_request(stroke_params);
+        let x0 = validation::finite("x0", x0)?;
+        let y0 = validation::finite("y0", y0)?;
+        let x1 = validation::finite("x1", x1)?;
+        let y1 = validation::finite("y1", y1)?;
+        let stroke_params = validation::required("stroke_params", stroke_params)?;
+
+        let stroke_params = StrokeParams::from_request(stroke_params, self.dpi)
+            .map_err(|e| e.in_field("stroke_params"))?;
+        // Nothing to draw for LTY_BLANK
+        let Some(stroke_params) = stroke_params else {
+            return Ok(Response::new(Empty {}));
+        };
 
-        self.event_loop_proxy
-            .send_event(UserEvent::DrawLine {
+        self.send_device_event(
+            device_id,
+            UserEvent::DrawLine {
                 p0: vello::kurbo::Point::new(x0, y0),
                 p1: vello::kurbo::Point::new(x1, y1),
                 stroke_params,
-            })
-            .map_err(|e| Status::from_error(Box::new(e)))?;
+            },
+        )?;
 
         let reply = Empty {};
         Ok(Response::new(reply))
@@ -130,31 +307,33 @@ impl GraphicsDevice for VelloGraphicsDevice {
         &self,
         request: Request<DrawPolylineRequest>,
     ) -> Result<Response<Empty>, Status> {
-        println!("{:?}", request);
+        log::debug!("{:?}", request);
 
         let DrawPolylineRequest {
             x,
             y,
             stroke_params,
+            device_id,
         } = request.into_inner();
 
-        let stroke_params = stroke_params.ok_or_else(|| {
-            Status::new(
-                tonic::Code::InvalidArgument,
-                "stroke_params must be specified",
-            )
-        })?;
+        let stroke_params = validation::required("stroke_params", stroke_params)?;
 
-        let path = utils::xy_to_path(x, y, false);
+        let path = utils::xy_to_path(x, y, false)?;
 
-        let stroke_params = StrokeParams::from_request(stroke_params);
+        let stroke_params = StrokeParams::from_request(stroke_params, self.dpi)
+            .map_err(|e| e.in_field("stroke_params"))?;
+        // Nothing to draw for LTY_BLANK
+        let Some(stroke_params) = stroke_params else {
+            return Ok(Response::new(Empty {}));
+        };
 
-        self.event_loop_proxy
-            .send_event(UserEvent::DrawPolyline {
+        self.send_device_event(
+            device_id,
+            UserEvent::DrawPolyline {
                 path,
                 stroke_params,
-            })
-            .map_err(|e| Status::from_error(Box::new(e)))?;
+            },
+        )?;
 
         let reply = Empty {};
         Ok(Response::new(reply))
@@ -164,26 +343,35 @@ impl GraphicsDevice for VelloGraphicsDevice {
         &self,
         request: Request<DrawPolygonRequest>,
     ) -> Result<Response<Empty>, Status> {
-        println!("{:?}", request);
+        log::debug!("{:?}", request);
 
         let DrawPolygonRequest {
             x,
             y,
             fill_color,
             stroke_params,
+            device_id,
+            fill_color_rgba,
         } = request.into_inner();
 
-        let fill_params = fill_color.map(FillParams::from_request);
-        let stroke_params = stroke_params.map(StrokeParams::from_request);
-        let path = utils::xy_to_path(x, y, true);
-
-        self.event_loop_proxy
-            .send_event(UserEvent::DrawPolygon {
+        let fill_params = FillParams::from_request(fill_color, fill_color_rgba)
+            .map_err(|e| e.in_field("fill_color_rgba"))?;
+        let stroke_params = stroke_params
+            .map(|params| StrokeParams::from_request(params, self.dpi))
+            .transpose()
+            .map_err(|e| e.in_field("stroke_params"))?
+            // LTY_BLANK
+            .flatten();
+        let path = utils::xy_to_path(x, y, true)?;
+
+        self.send_device_event(
+            device_id,
+            UserEvent::DrawPolygon {
                 path,
                 fill_params,
                 stroke_params,
-            })
-            .map_err(|e| Status::from_error(Box::new(e)))?;
+            },
+        )?;
 
         let reply = Empty {};
         Ok(Response::new(reply))
@@ -193,7 +381,7 @@ impl GraphicsDevice for VelloGraphicsDevice {
         &self,
         request: Request<DrawTextRequest>,
     ) -> Result<Response<Empty>, Status> {
-        println!("{:?}", request);
+        log::debug!("{:?}", request);
 
         let DrawTextRequest {
             x,
@@ -206,25 +394,368 @@ impl GraphicsDevice for VelloGraphicsDevice {
             family,
             angle,
             hadj,
+            device_id,
+            color_rgba,
         } = request.into_inner();
 
-        self.event_loop_proxy
-            .send_event(UserEvent::DrawText {
+        let x = validation::finite("x", x)?;
+        let y = validation::finite("y", y)?;
+        let size = validation::non_negative("size", size)?;
+        let lineheight = validation::non_negative("lineheight", lineheight)?;
+        let angle = validation::finite("angle", angle)?;
+        let hadj = validation::finite("hadj", hadj)?;
+        let color =
+            utils::color_from_request(color, color_rgba).map_err(|e| e.in_field("color_rgba"))?;
+
+        self.send_device_event(
+            device_id,
+            UserEvent::DrawText {
                 pos: vello::kurbo::Point::new(x, y),
                 text,
-                color: u32_to_color(color),
+                color,
                 size,
                 lineheight,
                 family,
                 angle,
                 hadj,
+            },
+        )?;
+
+        let reply = Empty {};
+        Ok(Response::new(reply))
+    }
+
+    async fn export_png(
+        &self,
+        request: Request<ExportPngRequest>,
+    ) -> Result<Response<ExportPngResponse>, Status> {
+        log::debug!("{:?}", request);
+
+        let ExportPngRequest {
+            path,
+            width,
+            height,
+            scale,
+            transparent,
+            device_id,
+        } = request.into_inner();
+
+        let scale = validation::non_negative("scale", scale)?;
+
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.send_device_event(
+            device_id,
+            UserEvent::ExportPng {
+                width,
+                height,
+                scale,
+                transparent,
+                reply,
+            },
+        )?;
+
+        let data = receiver
+            .await
+            .map_err(|e| Status::from_error(Box::new(e)))?
+            .map_err(|e| match e.downcast::<ValidationError>() {
+                // e.g. the image is too large
+                Ok(e) => e.into(),
+                Err(e) => Status::internal(format!("failed to export PNG: {e}")),
+            })?;
+
+        let data = match path {
+            Some(path) => {
+                std::fs::write(&path, data)
+                    .map_err(|e| Status::internal(format!("failed to write {path}: {e}")))?;
+                vec![]
+            }
+            None => data,
+        };
+
+        let reply = ExportPngResponse { data };
+        Ok(Response::new(reply))
+    }
+
+    async fn export_svg(
+        &self,
+        request: Request<ExportSvgRequest>,
+    ) -> Result<Response<ExportSvgResponse>, Status> {
+        log::debug!("{:?}", request);
+
+        let ExportSvgRequest {
+            path,
+            text_as_path,
+            device_id,
+        } = request.into_inner();
+
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.send_device_event(
+            device_id,
+            UserEvent::ExportSvg {
+                text_as_path,
+                reply,
+            },
+        )?;
+
+        let data = receiver
+            .await
+            .map_err(|e| Status::from_error(Box::new(e)))?;
+
+        let data = match path {
+            Some(path) => {
+                std::fs::write(&path, data)
+                    .map_err(|e| Status::internal(format!("failed to write {path}: {e}")))?;
+                String::new()
+            }
+            None => data,
+        };
+
+        let reply = ExportSvgResponse { data };
+        Ok(Response::new(reply))
+    }
+
+    async fn export_pdf(
+        &self,
+        request: Request<ExportPdfRequest>,
+    ) -> Result<Response<ExportPdfResponse>, Status> {
+        log::debug!("{:?}", request);
+
+        let ExportPdfRequest { path, device_id } = request.into_inner();
+
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.send_device_event(device_id, UserEvent::ExportPdf { reply })?;
+
+        let (data, discarded_pages) = receiver
+            .await
+            .map_err(|e| Status::from_error(Box::new(e)))?
+            .map_err(|e| Status::internal(format!("failed to export PDF: {e}")))?;
+
+        let data = match path {
+            Some(path) => {
+                std::fs::write(&path, data)
+                    .map_err(|e| Status::internal(format!("failed to write {path}: {e}")))?;
+                vec![]
+            }
+            None => data,
+        };
+
+        let reply = ExportPdfResponse {
+            data,
+            discarded_pages,
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn flush(&self, request: Request<FlushRequest>) -> Result<Response<Empty>, Status> {
+        log::debug!("{:?}", request);
+
+        let FlushRequest { device_id } = request.into_inner();
+
+        // Wait until the frame is rendered
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.send_device_event(device_id, UserEvent::Flush { reply })?;
+
+        receiver
+            .await
+            .map_err(|e| Status::from_error(Box::new(e)))?;
+
+        let reply = Empty {};
+        Ok(Response::new(reply))
+    }
+
+    async fn hold_flush(
+        &self,
+        request: Request<HoldFlushRequest>,
+    ) -> Result<Response<HoldFlushResponse>, Status> {
+        log::debug!("{:?}", request);
+
+        let HoldFlushRequest { device_id, level } = request.into_inner();
+
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.send_device_event(device_id, UserEvent::HoldFlush { level, reply })?;
+
+        let level = receiver
+            .await
+            .map_err(|e| Status::from_error(Box::new(e)))?;
+
+        let reply = HoldFlushResponse { level };
+        Ok(Response::new(reply))
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<GetStatusRequest>,
+    ) -> Result<Response<ServerStatus>, Status> {
+        log::debug!("{:?}", request);
+
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.event_sender
+            .send_event(0, UserEvent::GetStatus { reply })?;
+
+        let reply = receiver
+            .await
+            .map_err(|e| Status::from_error(Box::new(e)))?;
+
+        Ok(Response::new(reply))
+    }
+
+    async fn set_render_options(
+        &self,
+        request: Request<SetRenderOptionsRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        log::debug!("{:?}", request);
+
+        let SetRenderOptionsRequest {
+            antialiasing,
+            vsync,
+            adapter,
+        } = request.into_inner();
+
+        let antialiasing = AntialiasingMethod::try_from(antialiasing)
+            .map_err(|_| {
+                ValidationError::new("antialiasing", format!("unknown value {antialiasing}"))
             })
+            .map(Antialiasing::from_proto)?;
+
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.event_sender.send_event(
+            0,
+            UserEvent::SetRenderOptions {
+                antialiasing,
+                vsync,
+                adapter: adapter.as_deref().map(AdapterPreference::from),
+                reply,
+            },
+        )?;
+
+        receiver
+            .await
+            .map_err(|e| Status::from_error(Box::new(e)))?
+            .map_err(|e| Status::invalid_argument(format!("failed to set render options: {e}")))?;
+
+        let reply = Empty {};
+        Ok(Response::new(reply))
+    }
+
+    async fn get_device_info(
+        &self,
+        request: Request<GetDeviceInfoRequest>,
+    ) -> Result<Response<DeviceInfo>, Status> {
+        log::debug!("{:?}", request);
+
+        let GetDeviceInfoRequest { device_id } = request.into_inner();
+
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.send_device_event(device_id, UserEvent::GetDeviceInfo { reply })?;
+
+        let reply = receiver
+            .await
             .map_err(|e| Status::from_error(Box::new(e)))?;
 
+        Ok(Response::new(reply))
+    }
+
+    async fn set_resize_mode(
+        &self,
+        request: Request<SetResizeModeRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        log::debug!("{:?}", request);
+
+        let SetResizeModeRequest { device_id, mode } = request.into_inner();
+
+        let mode = ResizeMode::try_from(mode)
+            .map_err(|_| ValidationError::new("mode", format!("unknown value {mode}")))?;
+
+        self.send_device_event(device_id, UserEvent::SetResizeMode { mode })?;
+
+        let reply = Empty {};
+        Ok(Response::new(reply))
+    }
+
+    async fn set_device_extents(
+        &self,
+        request: Request<SetDeviceExtentsRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        log::debug!("{:?}", request);
+
+        let SetDeviceExtentsRequest {
+            device_id,
+            left,
+            right,
+            bottom,
+            top,
+        } = request.into_inner();
+
+        let extents = if [left, right, bottom, top] == [0.0; 4] {
+            None
+        } else {
+            let left = validation::finite("left", left)?;
+            let right = validation::finite("right", right)?;
+            let bottom = validation::finite("bottom", bottom)?;
+            let top = validation::finite("top", top)?;
+            if left == right {
+                return Err(ValidationError::new("right", "must differ from left").into());
+            }
+            if bottom == top {
+                return Err(ValidationError::new("top", "must differ from bottom").into());
+            }
+            Some(Extents {
+                left,
+                right,
+                bottom,
+                top,
+            })
+        };
+
+        self.send_device_event(device_id, UserEvent::SetDeviceExtents { extents })?;
+
         let reply = Empty {};
         Ok(Response::new(reply))
     }
 
+    async fn locator(
+        &self,
+        request: Request<LocatorRequest>,
+    ) -> Result<Response<LocatorResponse>, Status> {
+        log::debug!("{:?}", request);
+
+        let LocatorRequest { device_id } = request.into_inner();
+
+        // The reply is sent when the user clicks on the window
+        let (reply, receiver) = tokio::sync::oneshot::channel();
+        self.send_device_event(device_id, UserEvent::Locator { reply })?;
+
+        let reply = receiver
+            .await
+            .map_err(|e| Status::from_error(Box::new(e)))?;
+
+        Ok(Response::new(reply))
+    }
+
+    async fn watch_events(
+        &self,
+        request: Request<WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        log::debug!("{:?}", request);
+
+        let WatchEventsRequest { device_id } = request.into_inner();
+
+        let stream = BroadcastStream::new(self.notifier.subscribe()).filter_map(move |result| {
+            match result {
+                Ok(notification)
+                    if device_id.is_none() || device_id == Some(notification.device_id) =>
+                {
+                    Some(Ok(notification))
+                }
+                Ok(_) => None,
+                // If the client is too slow to receive, some notifications are lost
+                Err(_) => None,
+            }
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
     // async fn draw_path(
     //     &self,
     //     request: Request<DrawPathRequest>,
@@ -248,7 +779,7 @@ impl GraphicsDevice for VelloGraphicsDevice {
 
 pub struct ActiveRenderState<'a> {
     // The fields MUST be in this order, so that the surface is dropped before the window
-    surface: RenderSurface<'a>,
+    surface: GpuSurface<'a>,
     window: Arc<Window>,
 }
 
@@ -258,52 +789,41 @@ enum RenderState<'a> {
 }
 
 struct VelloApp<'a> {
-    context: RenderContext,
-    renderers: Vec<Option<Renderer>>,
-    state: RenderState<'a>,
-    scene: Scene,
-    background_color: Color,
+    context: GpuContext,
+    // Indexed by GpuContext::devices. The flag is whether the renderer uses
+    // vello's CPU shaders because the GPU ones are not usable on the device.
+    renderers: Vec<Option<(Renderer, bool)>>,
+    devices: HashMap<u32, DeviceState<'a>>,
+    // To route the window events to the device
+    window_ids: HashMap<WindowId, u32>,
     font_ctx: parley::FontContext,
+    offscreen_device: Option<OffscreenDevice>,
+    notifier: broadcast::Sender<DeviceNotification>,
+    // How long to wait after the last drawing operation before redrawing
+    redraw_delay: Duration,
+    render_options: RenderOptions,
+    // The logical pixels per inch of the devices
+    dpi: f64,
+    device_config: DeviceConfig,
+    open_devices: OpenDevices,
 }
 
-impl<'a> ApplicationHandler<UserEvent> for VelloApp<'a> {
+impl<'a> ApplicationHandler<DeviceEvent> for VelloApp<'a> {
     fn resumed(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
-        let RenderState::Suspended(cached_window) = &mut self.state else {
-            return;
-        };
-        let window = cached_window.take().unwrap_or_else(|| {
-            let attr = Window::default_attributes()
-                .with_title("test")
-                .with_inner_size(winit::dpi::LogicalSize::new(600.0, 600.0));
-            Arc::new(
-                event_loop
-                    .create_window(attr)
-                    .expect("failed to create window"),
-            )
-        });
-
-        let size = window.inner_size();
-        let surface = pollster::block_on(self.context.create_surface(
-            window.clone(),
-            size.width,
-            size.height,
-            vello::wgpu::PresentMode::AutoVsync,
-        ))
-        .expect("failed to create surface");
-
-        // Create a vello Renderer for the surface (using its device id)
-        self.renderers
-            .resize_with(self.context.devices.len(), || None);
-        self.renderers[surface.dev_id]
-            .get_or_insert_with(|| create_vello_renderer(&self.context, &surface));
-
-        // Save the Window and Surface to a state variable
-        self.state = RenderState::Active(ActiveRenderState { window, surface });
+        let device_ids = self.devices.keys().copied().collect::<Vec<_>>();
+        for device_id in device_ids {
+            // The device still works without the window (e.g. export)
+            if let Err(e) = self.resume_device(event_loop, device_id) {
+                log::error!("Failed to create window for device {device_id}: {e}");
+            }
+        }
     }
 
     fn suspended(&mut self, _event_loop: &winit::event_loop::ActiveEventLoop) {
-        if let RenderState::Active(state) = &self.state {
-            self.state = RenderState::Suspended(Some(state.window.clone()));
+        for device in self.devices.values_mut() {
+            if let RenderState::Active(state) = &device.render_state {
+                device.render_state = RenderState::Suspended(Some(state.window.clone()));
+            }
         }
     }
 
@@ -313,165 +833,405 @@ impl<'a> ApplicationHandler<UserEvent> for VelloApp<'a> {
         window_id: winit::window::WindowId,
         event: winit::event::WindowEvent,
     ) {
-        let render_state = match &mut self.state {
-            RenderState::Active(state) if state.window.id() == window_id => state,
-            _ => return,
+        let Some(&device_id) = self.window_ids.get(&window_id) else {
+            return;
+        };
+        let Some(device) = self.devices.get_mut(&device_id) else {
+            return;
         };
 
         match event {
             WindowEvent::CloseRequested => {
                 // TODO: can this always be executed immediately?
-                event_loop.exit();
+                self.close_device(event_loop, device_id);
             }
 
-            WindowEvent::Resized(size) => {
-                self.context
-                    .resize_surface(&mut render_state.surface, size.width, size.height);
+            WindowEvent::KeyboardInput { event, .. } if event.state.is_pressed() => {
+                match event.logical_key {
+                    Key::Named(NamedKey::ArrowLeft | NamedKey::PageUp) => {
+                        device.show_previous_page();
+                    }
+                    Key::Named(NamedKey::ArrowRight | NamedKey::PageDown) => {
+                        device.show_next_page();
+                    }
+                    Key::Named(NamedKey::Escape) => device.cancel_locator(),
+                    Key::Character(c) if c == "0" => device.reset_zoom(),
+                    _ => {}
+                }
+            }
+
+            WindowEvent::CursorMoved { position, .. } => {
+                let position = position.to_logical::<f64>(device.scale_factor);
+                device.on_cursor_moved((position.x, position.y).into());
             }
 
-            WindowEvent::RedrawRequested => {
-                // self.scene.reset();
+            WindowEvent::MouseInput { state, button, .. } => match button {
+                MouseButton::Left => device.on_left_button(state.is_pressed()),
+                MouseButton::Right if state == ElementState::Released => device.cancel_locator(),
+                _ => {}
+            },
+
+            WindowEvent::MouseWheel { delta, .. } => {
+                let amount = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y as f64,
+                    // Roughly, one line is 20 pixels
+                    MouseScrollDelta::PixelDelta(pos) => pos.y / device.scale_factor / 20.0,
+                };
+                device.zoom_at_cursor((amount * 0.1).exp());
+            }
 
-                let surface = &render_state.surface;
-                let width = surface.config.width;
-                let height = surface.config.height;
+            WindowEvent::Resized(size) => {
+                // e.g. minimized. wgpu panics on configuring a surface of
+                // zero size, so the surface (and the page size) is kept
+                // until the window is restored.
+                if size.width == 0 || size.height == 0 {
+                    return;
+                }
 
-                let device_handle = &self.context.devices[surface.dev_id];
+                if let RenderState::Active(state) = &mut device.render_state {
+                    self.context
+                        .resize_surface(&mut state.surface, size.width, size.height);
+                }
 
-                let surface_texture = surface
-                    .surface
-                    .get_current_texture()
-                    .expect("failed to get surface texture");
-
-                if let Some(renderer) = self.renderers[surface.dev_id].as_mut() {
-                    renderer
-                        .render_to_surface(
-                            &device_handle.device,
-                            &device_handle.queue,
-                            &self.scene,
-                            &surface_texture,
-                            &vello::RenderParams {
-                                base_color: self.background_color,
-                                width,
-                                height,
-                                antialiasing_method: AaConfig::Msaa16,
-                            },
-                        )
-                        .expect("failed to render");
+                if device.resize_mode == ResizeMode::Notify {
+                    let (width, height) = device.size();
+                    // It's fine if no one is listening
+                    let _ = self.notifier.send(DeviceNotification {
+                        device_id,
+                        kind: Some(device_notification::Kind::Resized(Resized {
+                            width,
+                            height,
+                        })),
+                    });
                 }
+                device.request_redraw();
+            }
 
-                surface_texture.present();
-                device_handle.device.poll(vello::wgpu::Maintain::Poll);
+            // Resized event follows this, so the surface is resized there
+            WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                device.scale_factor = scale_factor;
+                device.request_redraw();
             }
+
+            WindowEvent::RedrawRequested => self.render_device(device_id),
             _ => (),
         }
     }
 
-    fn user_event(&mut self, event_loop: &winit::event_loop::ActiveEventLoop, event: UserEvent) {
-        let render_state = match &mut self.state {
-            RenderState::Active(state) => state,
-            _ => return,
+    fn user_event(&mut self, event_loop: &winit::event_loop::ActiveEventLoop, event: DeviceEvent) {
+        let DeviceEvent { device_id, event } = event;
+
+        match event {
+            UserEvent::OpenDevice { reply } => {
+                self.devices
+                    .entry(device_id)
+                    .or_insert_with(|| DeviceState::new(device_id, self.device_config.clone()));
+                // Note: this assumes the event loop is already resumed, which
+                // is always true on desktop platforms.
+                let result = self.resume_device(event_loop, device_id);
+                if let Err(e) = &result {
+                    log::error!("Failed to create window for device {device_id}: {e}");
+                    self.open_devices.lock().unwrap().remove(&device_id);
+                    self.devices.remove(&device_id);
+                }
+                let _ = reply.send(result);
+            }
+            UserEvent::CloseWindow => self.close_device(event_loop, device_id),
+            UserEvent::Flush { reply } => {
+                // If the device doesn't exist, the reply is dropped and the
+                // client gets an error
+                if self.devices.contains_key(&device_id) {
+                    self.render_device(device_id);
+                    let _ = reply.send(());
+                }
+            }
+            UserEvent::SetRenderOptions {
+                antialiasing,
+                vsync,
+                adapter,
+                reply,
+            } => {
+                let result = self.set_render_options(antialiasing, vsync, adapter);
+                // If the adapter is changed, the surfaces are recreated here
+                let device_ids = self.devices.keys().copied().collect::<Vec<_>>();
+                for device_id in device_ids {
+                    if let Err(e) = self.resume_device(event_loop, device_id) {
+                        log::error!("Failed to create window for device {device_id}: {e}");
+                    }
+                    if let Some(device) = self.devices.get(&device_id) {
+                        device.request_redraw();
+                    }
+                }
+                let _ = reply.send(result);
+            }
+            UserEvent::GetStatus { reply } => {
+                let _ = reply.send(self.status());
+            }
+            event => {
+                // Instead of redrawing on every operation, mark the device as
+                // dirty. It is redrawn in about_to_wait(), i.e. after all the
+                // pending events are processed. Queries and exports don't
+                // change what's displayed.
+                if !self.apply_user_event(device_id, event) {
+                    return;
+                }
+                if let Some(device) = self.devices.get_mut(&device_id) {
+                    let now = Instant::now();
+                    device.last_change = Some(now);
+                    device.first_change.get_or_insert(now);
+                }
+            }
+        }
+    }
+
+    fn about_to_wait(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
+        let now = Instant::now();
+        let mut next_deadline: Option<Instant> = None;
+
+        for device in self.devices.values_mut() {
+            let (Some(first_change), Some(last_change)) = (device.first_change, device.last_change)
+            else {
+                continue;
+            };
+            // The changes are rendered when the hold is released
+            if device.is_held() {
+                continue;
+            }
+
+            // Wait until the client stops drawing for a while, but not longer
+            // than MAX_REDRAW_WAIT since the first change
+            let deadline = (last_change + self.redraw_delay)
+                .min(first_change + MAX_REDRAW_WAIT.max(self.redraw_delay));
+            if deadline <= now {
+                device.last_change = None;
+                device.first_change = None;
+                device.request_redraw();
+            } else {
+                next_deadline = Some(next_deadline.map_or(deadline, |d| d.min(deadline)));
+            }
+        }
+
+        match next_deadline {
+            Some(deadline) => event_loop.set_control_flow(ControlFlow::WaitUntil(deadline)),
+            None => event_loop.set_control_flow(ControlFlow::Wait),
+        }
+    }
+}
+
+impl<'a> VelloApp<'a> {
+    /// Creates the window (if not yet) and the surface of the device. Only the
+    /// failure to create the window is an error; if the surface can't be
+    /// created, the device is kept suspended.
+    fn resume_device(
+        &mut self,
+        event_loop: &winit::event_loop::ActiveEventLoop,
+        device_id: u32,
+    ) -> anyhow::Result<()> {
+        let Some(device) = self.devices.get_mut(&device_id) else {
+            return Ok(());
+        };
+        let RenderState::Suspended(cached_window) = &mut device.render_state else {
+            return Ok(());
+        };
+        let window = match cached_window.take() {
+            Some(window) => window,
+            None => {
+                let (width, height) = device.config.size;
+                let attr = Window::default_attributes()
+                    .with_title(&device.config.title)
+                    .with_inner_size(winit::dpi::LogicalSize::new(width as f64, height as f64));
+                Arc::new(event_loop.create_window(attr)?)
+            }
+        };
+        device.scale_factor = window.scale_factor();
+        self.window_ids.insert(window.id(), device_id);
+
+        let present_mode = self.render_options.present_mode();
+        let preference = self.context.adapter_preference.clone();
+        let surface =
+            match create_surface(&mut self.context, window.clone(), present_mode, &preference) {
+                Ok(surface) => surface,
+                Err(e) => {
+                    // e.g. no GPU on a VM or a remote desktop. This applies only to
+                    // this window; the others still try the preferred adapter.
+                    log::warn!("Failed to create surface: {e}. Trying the software adapter");
+                    let result = create_surface(
+                        &mut self.context,
+                        window.clone(),
+                        present_mode,
+                        &AdapterPreference::Software,
+                    );
+                    match result {
+                        Ok(surface) => surface,
+                        Err(e) => {
+                            // The device still works without the window (e.g. export)
+                            log::error!(
+                                "Failed to create surface: {e}. The window is not rendered"
+                            );
+                            device.render_state = RenderState::Suspended(Some(window));
+                            return Ok(());
+                        }
+                    }
+                }
+            };
+
+        // Create a vello Renderer for the surface (using its device id)
+        self.ensure_renderer(surface.dev_id, surface.format, false);
+
+        // Save the Window and Surface to a state variable
+        let Some(device) = self.devices.get_mut(&device_id) else {
+            return Ok(());
+        };
+        device.render_state = RenderState::Active(ActiveRenderState { window, surface });
+        device.update_title();
+
+        // Now the actual size is known
+        device.fit_placeholder_page();
+        Ok(())
+    }
+
+    /// Creates a renderer for the wgpu device if it doesn't exist yet. The
+    /// GPU shaders are tried first unless use_cpu is true.
+    fn ensure_renderer(
+        &mut self,
+        dev_id: usize,
+        surface_format: vello::wgpu::TextureFormat,
+        use_cpu: bool,
+    ) {
+        self.renderers
+            .resize_with(self.context.devices.len(), || None);
+        if self.renderers[dev_id].is_some() {
+            return;
+        }
+
+        let device_handle = &self.context.devices[dev_id];
+        log::info!("Using adapter: {:?}", device_handle.adapter.get_info());
+        match render_options::create_renderer(
+            &device_handle.device,
+            Some(surface_format),
+            self.render_options.antialiasing,
+            use_cpu,
+        ) {
+            Ok(renderer) => self.renderers[dev_id] = Some(renderer),
+            Err(e) => log::error!("Failed to create renderer: {e}. The window is not rendered"),
+        }
+    }
+
+    /// Drops the device along with its window. The server exits when the last
+    /// device is closed.
+    fn close_device(&mut self, event_loop: &winit::event_loop::ActiveEventLoop, device_id: u32) {
+        self.open_devices.lock().unwrap().remove(&device_id);
+        if let Some(device) = self.devices.remove(&device_id) {
+            if let Some(window_id) = device.window_id() {
+                self.window_ids.remove(&window_id);
+            }
+        }
+
+        if self.devices.is_empty() {
+            event_loop.exit();
+        }
+    }
+
+    /// Applies the event to the device. Returns true if the scene is changed,
+    /// i.e. the window needs to be redrawn.
+    fn apply_user_event(&mut self, device_id: u32, event: UserEvent) -> bool {
+        let Some(device) = self.devices.get_mut(&device_id) else {
+            // The reply channel (if any) is dropped here, so the client gets an error
+            log::warn!("Device {device_id} doesn't exist");
+            return false;
         };
 
+        let changes_scene = matches!(
+            event,
+            UserEvent::NewPage { .. }
+                | UserEvent::DrawCircle { .. }
+                | UserEvent::DrawLine { .. }
+                | UserEvent::DrawPolyline { .. }
+                | UserEvent::DrawPolygon { .. }
+                | UserEvent::DrawText { .. }
+        );
+
         match event {
-            UserEvent::CloseWindow => {
-                event_loop.exit();
+            // These are handled by the caller
+            UserEvent::OpenDevice { .. }
+            | UserEvent::CloseWindow
+            | UserEvent::Flush { .. }
+            | UserEvent::SetRenderOptions { .. }
+            | UserEvent::GetStatus { .. } => {}
+            UserEvent::NewPage {
+                size,
+                background_color,
+            } => device.new_page(size, background_color),
+            UserEvent::GetDeviceInfo { reply } => {
+                let (width, height) = device.size();
+                let _ = reply.send(DeviceInfo {
+                    width,
+                    height,
+                    scale_factor: device.scale_factor,
+                    dpi: self.dpi,
+                });
+            }
+            UserEvent::Locator { reply } => device.set_locator_reply(reply),
+            UserEvent::HoldFlush { level, reply } => {
+                let _ = reply.send(device.hold_flush(level));
             }
-            UserEvent::NewPage => {
-                self.scene.reset();
-                render_state.window.request_redraw();
+            UserEvent::SetResizeMode { mode } => {
+                device.resize_mode = mode;
+                device.request_redraw();
             }
+            UserEvent::SetDeviceExtents { extents } => device.set_extents(extents),
+            // Note: the geometries are converted to the pixels of the page,
+            // but the line widths and the font sizes are not, as they are
+            // specified in their own units.
             UserEvent::DrawCircle {
                 center,
                 radius,
                 fill_params,
                 stroke_params,
             } => {
-                let circle = vello::kurbo::Circle::new(center, radius);
-
-                if let Some(fill_params) = fill_params {
-                    self.scene.fill(
-                        vello::peniko::Fill::NonZero,
-                        vello::kurbo::Affine::IDENTITY,
-                        fill_params.color,
-                        None,
-                        &circle,
-                    );
-                }
-
-                if let Some(stroke_params) = stroke_params {
-                    self.scene.stroke(
-                        &stroke_params.stroke,
-                        vello::kurbo::Affine::IDENTITY,
-                        stroke_params.color,
-                        None,
-                        &circle,
-                    );
-                }
-
-                // TODO: set a flag and redraw lazily
-                render_state.window.request_redraw();
+                let transform = device.device_transform();
+                // R specifies the radius in the x direction
+                let radius_scale = transform.as_coeffs()[0].abs();
+                device.push_display_item(DisplayItem::Circle {
+                    center: transform * center,
+                    radius: radius * radius_scale,
+                    fill_params,
+                    stroke_params,
+                })
             }
             UserEvent::DrawLine {
                 p0,
                 p1,
                 stroke_params,
             } => {
-                let line = vello::kurbo::Line::new(p0, p1);
-
-                self.scene.stroke(
-                    &stroke_params.stroke,
-                    vello::kurbo::Affine::IDENTITY,
-                    stroke_params.color,
-                    None,
-                    &line,
-                );
-
-                // TODO: set a flag and redraw lazily
-                render_state.window.request_redraw();
+                let transform = device.device_transform();
+                device.push_display_item(DisplayItem::Line {
+                    p0: transform * p0,
+                    p1: transform * p1,
+                    stroke_params,
+                })
             }
             UserEvent::DrawPolyline {
                 path,
                 stroke_params,
             } => {
-                self.scene.stroke(
-                    &stroke_params.stroke,
-                    vello::kurbo::Affine::IDENTITY,
-                    stroke_params.color,
-                    None,
-                    &path,
-                );
-
-                // TODO: set a flag and redraw lazily
-                render_state.window.request_redraw();
+                let transform = device.device_transform();
+                device.push_display_item(DisplayItem::Polyline {
+                    path: transform * path,
+                    stroke_params,
+                })
             }
             UserEvent::DrawPolygon {
                 path,
                 fill_params,
                 stroke_params,
             } => {
-                if let Some(fill_params) = fill_params {
-                    self.scene.fill(
-                        vello::peniko::Fill::NonZero,
-                        vello::kurbo::Affine::IDENTITY,
-                        fill_params.color,
-                        None,
-                        &path,
-                    );
-                }
-
-                if let Some(stroke_params) = stroke_params {
-                    self.scene.stroke(
-                        &stroke_params.stroke,
-                        vello::kurbo::Affine::IDENTITY,
-                        stroke_params.color,
-                        None,
-                        &path,
-                    );
-                }
-
-                // TODO: set a flag and redraw lazily
-                render_state.window.request_redraw();
+                let transform = device.device_transform();
+                device.push_display_item(DisplayItem::Polygon {
+                    path: transform * path,
+                    fill_params,
+                    stroke_params,
+                })
             }
             UserEvent::DrawText {
                 pos,
@@ -483,92 +1243,363 @@ impl<'a> ApplicationHandler<UserEvent> for VelloApp<'a> {
                 angle,
                 hadj,
             } => {
-                // Note: parley is probably a little bit overkill, but it seems
-                // this is the only interface.
-                let mut layout_ctx: parley::LayoutContext<vello::peniko::Brush> =
-                    parley::LayoutContext::new();
-                let mut layout_builder =
-                    layout_ctx.ranged_builder(&mut self.font_ctx, text.as_str(), 1.0); // TODO: should scale be configurable?
-                layout_builder.push_default(&parley::StyleProperty::FontSize(size));
-                layout_builder.push_default(&parley::StyleProperty::LineHeight(lineheight));
-                layout_builder.push_default(&parley::StyleProperty::FontStack(
-                    parley::FontStack::Source("system-iu"), // TODO: specify family
-                ));
-                // TODO: use build_into() to reuse a Layout?
-                let mut layout = layout_builder.build(text.as_str());
-                layout.break_all_lines(None); // It seems this is mandatory, otherwise no text is drawn. Why?
-                layout.align(None, parley::Alignment::Start);
-
-                let width = layout.width();
-                let transform = vello::kurbo::Affine::translate((-(width * hadj) as f64, 0.0))
+                let (glyph_runs, width) = text::layout(&mut self.font_ctx, &text, size, lineheight);
+                // The angle is always counterclockwise on the screen, so only
+                // the position is converted
+                let pos = device.device_transform() * pos;
+                let transform = Affine::translate((-(width * hadj) as f64, 0.0))
                     .then_rotate(-angle as f64)
                     .then_translate((pos.x, pos.y).into());
 
-                for line in layout.lines() {
-                    let vadj = line.metrics().ascent * 0.5;
-                    for item in line.items() {
-                        // ignore inline box
-                        let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item else {
-                            continue;
-                        };
-
-                        let mut x = glyph_run.offset();
-                        let y = glyph_run.baseline() - vadj;
-                        let run = glyph_run.run();
-
-                        let font = run.font();
-                        let font_size = run.font_size();
-
-                        // TODO:  It seems this is to handle italic. Is this necessary?
-                        //
-                        // https://github.com/linebender/parley/blob/be9e9ab3fc3fe92b3887048d5123c963cffac3d5/examples/vello_editor/src/text.rs#L364-L366
-                        // https://docs.rs/kurbo/latest/kurbo/struct.Affine.html#method.skew
-                        //
-                        // let glyph_xform = run.synthesis().skew().map(|angle| {
-                        //     vello::kurbo::Affine::skew(angle.to_radians().tan() as f64, 0.0)
-                        // });
-
-                        let coords = run
-                            .normalized_coords()
-                            .iter()
-                            .map(|coord| {
-                                vello::skrifa::instance::NormalizedCoord::from_bits(*coord)
-                            })
-                            .collect::<Vec<_>>();
-
-                        // TODO: vello and parley uses different versions of font
-                        let font = {
-                            let raw = font.clone().data.into_raw_parts();
-                            let data = vello::peniko::Blob::from_raw_parts(raw.0, raw.1);
-                            vello::peniko::Font::new(data, font.index)
-                        };
-
-                        self.scene
-                            .draw_glyphs(&font)
-                            .brush(color)
-                            .transform(transform)
-                            .font_size(font_size)
-                            .normalized_coords(&coords)
-                            .draw(
-                                vello::peniko::Fill::NonZero,
-                                glyph_run.glyphs().map(|g| {
-                                    let gx = x + g.x;
-                                    let gy = y - g.y;
-                                    x += g.advance;
-                                    vello::Glyph {
-                                        id: g.id as _,
-                                        x: gx,
-                                        y: gy,
-                                    }
-                                }),
-                            );
-                    }
+                device.push_display_item(DisplayItem::Text {
+                    text,
+                    family,
+                    size,
+                    color,
+                    transform,
+                    glyph_runs,
+                });
+            }
+            UserEvent::ExportPng {
+                width,
+                height,
+                scale,
+                transparent,
+                reply,
+            } => {
+                let result = self.export_png(device_id, width, height, scale, transparent);
+                // If the receiver is already dropped, there's nothing we can do
+                let _ = reply.send(result);
+            }
+            UserEvent::ExportSvg {
+                text_as_path,
+                reply,
+            } => {
+                let svg = export::page_to_svg(device.current_page(), text_as_path, self.dpi);
+                let _ = reply.send(svg);
+            }
+            UserEvent::ExportPdf { reply } => {
+                let result = export::pages_to_pdf(device.pages(), self.dpi)
+                    .map(|data| (data, device.discarded_pages()));
+                let _ = reply.send(result);
+            }
+        };
+
+        changes_scene
+    }
+
+    fn export_png(
+        &mut self,
+        device_id: u32,
+        width: u32,
+        height: u32,
+        scale: f64,
+        transparent: bool,
+    ) -> anyhow::Result<Vec<u8>> {
+        let device = self
+            .devices
+            .get(&device_id)
+            .ok_or_else(|| anyhow::anyhow!("device {device_id} doesn't exist"))?;
+
+        let page = device.current_page();
+        let (default_width, default_height) = page.size();
+        let width = if width == 0 { default_width } else { width };
+        let height = if height == 0 { default_height } else { height };
+        let scale = if scale > 0.0 { scale } else { 1.0 };
+
+        let mut scene = Scene::new();
+        page.build_scene(&mut scene, Affine::scale(scale));
+
+        let params = vello::RenderParams {
+            base_color: if transparent {
+                Color::TRANSPARENT
+            } else {
+                page.background_color()
+            },
+            width: (width as f64 * scale).round() as u32,
+            height: (height as f64 * scale).round() as u32,
+            antialiasing_method: self.render_options.antialiasing.aa_config(),
+        };
+
+        let rgba = self.render_offscreen(&scene, &params)?;
+        export::encode_png(&rgba, params.width, params.height, self.dpi * scale)
+    }
+
+    /// Renders the scene into an RGBA image. If there's no window (e.g. in
+    /// headless mode), a dedicated offscreen device is used.
+    fn render_offscreen(
+        &mut self,
+        scene: &Scene,
+        params: &vello::RenderParams,
+    ) -> anyhow::Result<Vec<u8>> {
+        // Any renderer for the windows will do
+        if let Some((dev_id, renderer)) = self
+            .renderers
+            .iter_mut()
+            .enumerate()
+            .find_map(|(dev_id, renderer)| Some((dev_id, &mut renderer.as_mut()?.0)))
+        {
+            let device_handle = &self.context.devices[dev_id];
+            return offscreen::render_to_rgba(
+                &device_handle.device,
+                &device_handle.queue,
+                renderer,
+                scene,
+                params,
+            );
+        }
+
+        if self.offscreen_device.is_none() {
+            let device = pollster::block_on(OffscreenDevice::new(
+                &self.context.instance,
+                &self.context.adapter_preference,
+                self.render_options.antialiasing,
+            ))?;
+            log::info!(
+                "Using adapter for offscreen rendering: {:?}",
+                device.adapter_info
+            );
+            self.offscreen_device = Some(device);
+        }
+        let device = self.offscreen_device.as_mut().unwrap();
+        offscreen::render_to_rgba(
+            &device.device,
+            &device.queue,
+            &mut device.renderer,
+            scene,
+            params,
+        )
+    }
+
+    /// Changes the render options. If the adapter is changed, all the GPU
+    /// resources are discarded and the devices are suspended, so the caller
+    /// needs to resume them.
+    fn set_render_options(
+        &mut self,
+        antialiasing: Option<Antialiasing>,
+        vsync: Option<bool>,
+        adapter: Option<AdapterPreference>,
+    ) -> anyhow::Result<()> {
+        if let Some(antialiasing) = antialiasing {
+            self.render_options.antialiasing = antialiasing;
+        }
+        if let Some(vsync) = vsync {
+            self.render_options.vsync = vsync;
+        }
+
+        if let Some(adapter) = adapter {
+            // Fail before discarding anything if no adapter matches
+            pollster::block_on(render_options::select_adapter(
+                &self.context.instance,
+                &adapter,
+                None,
+            ))?;
+
+            // The surfaces must be dropped before the devices
+            for device in self.devices.values_mut() {
+                if let RenderState::Active(state) = &device.render_state {
+                    device.render_state = RenderState::Suspended(Some(state.window.clone()));
                 }
+            }
+            self.renderers.clear();
+            self.offscreen_device = None;
+            self.context = GpuContext::new(adapter);
+            return Ok(());
+        }
 
-                // TODO: set a flag and redraw lazily
-                render_state.window.request_redraw();
+        // Otherwise, recreate the renderers and reconfigure the surfaces in
+        // place. A renderer on the CPU shaders doesn't retry the GPU ones.
+        let cpu_shaders = self
+            .renderers
+            .iter_mut()
+            .map(|renderer| renderer.take().is_some_and(|(_, use_cpu)| use_cpu))
+            .collect::<Vec<_>>();
+        self.offscreen_device = None;
+        let mut surfaces = Vec::new();
+        for device in self.devices.values_mut() {
+            if let RenderState::Active(state) = &mut device.render_state {
+                self.context
+                    .set_present_mode(&mut state.surface, self.render_options.present_mode());
+                surfaces.push((state.surface.dev_id, state.surface.format));
             }
+        }
+        for (dev_id, surface_format) in surfaces {
+            let use_cpu = cpu_shaders.get(dev_id).copied().unwrap_or(false);
+            self.ensure_renderer(dev_id, surface_format, use_cpu);
+        }
+
+        Ok(())
+    }
+
+    /// Reports which adapters and shaders are used for rendering.
+    fn status(&self) -> ServerStatus {
+        let adapter_status =
+            |info: vello::wgpu::AdapterInfo, cpu_shaders, offscreen, fallback| AdapterStatus {
+                name: info.name,
+                backend: format!("{:?}", info.backend),
+                device_type: format!("{:?}", info.device_type),
+                cpu_shaders,
+                offscreen,
+                fallback,
+            };
+
+        let mut adapters = self
+            .context
+            .devices
+            .iter()
+            .enumerate()
+            .map(|(dev_id, device_handle)| {
+                adapter_status(
+                    device_handle.adapter.get_info(),
+                    matches!(self.renderers.get(dev_id), Some(Some((_, true)))),
+                    false,
+                    device_handle.preference != self.context.adapter_preference,
+                )
+            })
+            .collect::<Vec<_>>();
+        if let Some(device) = &self.offscreen_device {
+            adapters.push(adapter_status(
+                device.adapter_info.clone(),
+                device.use_cpu,
+                true,
+                false,
+            ));
+        }
+
+        ServerStatus {
+            adapters,
+            num_devices: self.devices.len() as u32,
+        }
+    }
+
+    /// Renders the device's scene to its window immediately.
+    fn render_device(&mut self, device_id: u32) {
+        let Some(device) = self.devices.get_mut(&device_id) else {
+            return;
         };
+        // Everything so far is going to be rendered
+        device.last_change = None;
+        device.first_change = None;
+
+        let RenderState::Active(state) = &device.render_state else {
+            return;
+        };
+        // Nothing to render into while minimized
+        let window_size = state.window.inner_size();
+        if window_size.width == 0 || window_size.height == 0 {
+            return;
+        }
+
+        let surface = &state.surface;
+        let width = surface.config.width;
+        let height = surface.config.height;
+
+        let device_handle = &self.context.devices[surface.dev_id];
+
+        // Apply the transform only when needed, as this requires copying the scene
+        let transform = device.view_transform();
+        let mut transformed_scene;
+        let scene = if transform == Affine::IDENTITY {
+            device.displayed_scene()
+        } else {
+            transformed_scene = Scene::new();
+            transformed_scene.append(device.displayed_scene(), Some(transform));
+            &transformed_scene
+        };
+
+        let surface_texture = match surface.surface.get_current_texture() {
+            Ok(surface_texture) => surface_texture,
+            // The surface needs to be reconfigured (e.g. the window was
+            // resized or moved to another display). Retry on the next frame.
+            Err(e @ (vello::wgpu::SurfaceError::Lost | vello::wgpu::SurfaceError::Outdated)) => {
+                log::warn!("Failed to get surface texture: {e}. Reconfiguring the surface");
+                surface
+                    .surface
+                    .configure(&device_handle.device, &surface.config);
+                state.window.request_redraw();
+                return;
+            }
+            // e.g. timeout. The next redraw may succeed
+            Err(e) => {
+                log::warn!("Failed to get surface texture: {e}");
+                return;
+            }
+        };
+
+        if let Some((renderer, _)) = self.renderers[surface.dev_id].as_mut() {
+            // Catch wgpu's errors so that they don't reach the uncaptured
+            // error handler, which panics
+            device_handle
+                .device
+                .push_error_scope(vello::wgpu::ErrorFilter::Validation);
+            let result = renderer.render_to_surface(
+                &device_handle.device,
+                &device_handle.queue,
+                scene,
+                &surface_texture,
+                &vello::RenderParams {
+                    base_color: device.viewing_page().background_color(),
+                    width,
+                    height,
+                    antialiasing_method: self.render_options.antialiasing.aa_config(),
+                },
+            );
+            let wgpu_error = pollster::block_on(device_handle.device.pop_error_scope());
+
+            // Skip the frame. The window keeps showing the previous frame.
+            if let Err(e) = result {
+                log::error!("Failed to render: {e}");
+                return;
+            }
+            if let Some(e) = wgpu_error {
+                log::error!("Failed to render: {e}");
+                return;
+            }
+        }
+
+        surface_texture.present();
+        device_handle.device.poll(vello::wgpu::Maintain::Poll);
+    }
+
+    /// Processes the UserEvents without winit's event loop.
+    fn run_headless(&mut self, receiver: std::sync::mpsc::Receiver<DeviceEvent>) {
+        for DeviceEvent { device_id, event } in receiver.iter() {
+            match event {
+                UserEvent::OpenDevice { reply } => {
+                    self.devices
+                        .entry(device_id)
+                        .or_insert_with(|| DeviceState::new(device_id, self.device_config.clone()));
+                    let _ = reply.send(Ok(()));
+                }
+                UserEvent::CloseWindow => {
+                    self.open_devices.lock().unwrap().remove(&device_id);
+                    self.devices.remove(&device_id);
+                    if self.devices.is_empty() {
+                        break;
+                    }
+                }
+                // Nothing to render without window
+                UserEvent::Flush { reply } => {
+                    if self.devices.contains_key(&device_id) {
+                        let _ = reply.send(());
+                    }
+                }
+                UserEvent::SetRenderOptions {
+                    antialiasing,
+                    vsync,
+                    adapter,
+                    reply,
+                } => {
+                    let _ = reply.send(self.set_render_options(antialiasing, vsync, adapter));
+                }
+                UserEvent::GetStatus { reply } => {
+                    let _ = reply.send(self.status());
+                }
+                event => {
+                    self.apply_user_event(device_id, event);
+                }
+            }
+        }
     }
 }
 
@@ -583,10 +1614,55 @@ struct StrokeParams {
     stroke: vello::kurbo::Stroke,
 }
 
-#[derive(Debug, Clone)]
+/// A UserEvent with the ID of the target device.
+#[derive(Debug)]
+struct DeviceEvent {
+    device_id: u32,
+    event: UserEvent,
+}
+
+#[derive(Debug)]
 enum UserEvent {
+    // Fails if the window can't be created
+    OpenDevice {
+        reply: tokio::sync::oneshot::Sender<anyhow::Result<()>>,
+    },
     CloseWindow,
-    NewPage,
+    NewPage {
+        size: Option<(u32, u32)>,
+        background_color: Option<Color>,
+    },
+    Flush {
+        reply: tokio::sync::oneshot::Sender<()>,
+    },
+    GetDeviceInfo {
+        reply: tokio::sync::oneshot::Sender<DeviceInfo>,
+    },
+    SetResizeMode {
+        mode: ResizeMode,
+    },
+    // None means the default (the pixels of the page)
+    SetDeviceExtents {
+        extents: Option<Extents>,
+    },
+    Locator {
+        reply: tokio::sync::oneshot::Sender<LocatorResponse>,
+    },
+    HoldFlush {
+        level: i32,
+        reply: tokio::sync::oneshot::Sender<u32>,
+    },
+    // Not specific to the device; device_id is ignored
+    GetStatus {
+        reply: tokio::sync::oneshot::Sender<ServerStatus>,
+    },
+    // Not specific to the device; device_id is ignored
+    SetRenderOptions {
+        antialiasing: Option<Antialiasing>,
+        vsync: Option<bool>,
+        adapter: Option<AdapterPreference>,
+        reply: tokio::sync::oneshot::Sender<anyhow::Result<()>>,
+    },
     DrawCircle {
         center: vello::kurbo::Point,
         radius: f64,
@@ -619,47 +1695,263 @@ enum UserEvent {
         angle: f32,
         hadj: f32,
     },
+    ExportPng {
+        width: u32,
+        height: u32,
+        scale: f64,
+        transparent: bool,
+        reply: tokio::sync::oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    },
+    ExportSvg {
+        text_as_path: bool,
+        reply: tokio::sync::oneshot::Sender<String>,
+    },
+    // The PDF and the number of the pages missing from it
+    ExportPdf {
+        reply: tokio::sync::oneshot::Sender<anyhow::Result<(Vec<u8>, u32)>>,
+    },
 }
 
-fn create_vello_renderer(render_cx: &RenderContext, surface: &RenderSurface) -> Renderer {
-    Renderer::new(
-        &render_cx.devices[surface.dev_id].device,
-        RendererOptions {
-            surface_format: Some(surface.format),
-            use_cpu: false,
-            antialiasing_support: vello::AaSupport::all(),
-            num_init_threads: NonZeroUsize::new(1),
-        },
-    )
-    .expect("Couldn't create renderer")
+fn create_surface<'w>(
+    context: &mut GpuContext,
+    window: Arc<Window>,
+    present_mode: vello::wgpu::PresentMode,
+    preference: &AdapterPreference,
+) -> anyhow::Result<GpuSurface<'w>> {
+    // The window can be minimized (e.g. when the adapter is changed). wgpu
+    // panics on zero size, and the surface is resized on restore anyway.
+    let size = window.inner_size();
+    let surface = pollster::block_on(context.create_surface(
+        window,
+        size.width.max(1),
+        size.height.max(1),
+        present_mode,
+        preference,
+    ))?;
+    Ok(surface)
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum SizeUnit {
+    Px,
+    In,
+}
+
+fn parse_color(s: &str) -> Result<Color, String> {
+    parse_hex_color(s).map(utils::u32_to_color)
+}
+
+fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(dpi) if dpi.is_finite() && dpi > 0.0 => Ok(dpi),
+        _ => Err(format!("must be a positive number, but got {s}")),
+    }
+}
+
+/// A graphics device server for R
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Run without any window. The scene is rendered offscreen only when requested.
+    #[arg(long)]
+    headless: bool,
+
+    /// Milliseconds to wait after the last drawing operation before redrawing
+    /// the window. If 0, the window is redrawn at most once per batch of
+    /// requests. While the client keeps drawing, the window is still redrawn
+    /// every 100 ms or every this many milliseconds, whichever is longer.
+    #[arg(long, default_value_t = 0)]
+    redraw_delay: u64,
+
+    /// The resolution of the devices in logical pixels per inch. This affects
+    /// the line widths in physical units and the physical size of the exports.
+    #[arg(long, default_value_t = BASE_DPI, value_parser = parse_positive)]
+    dpi: f64,
+
+    /// The address to listen on. If the port is 0 (the default), a free port
+    /// is chosen. The actual address is written to the discovery file, which
+    /// vellogd-cli reads by default.
+    #[arg(long, default_value = "[::1]:0")]
+    address: SocketAddr,
+
+    /// Listen on the Unix domain socket at this path instead of TCP. Only the
+    /// owner can connect to the socket.
+    #[cfg(unix)]
+    #[arg(long)]
+    unix: Option<std::path::PathBuf>,
+
+    /// The initial width of the windows in --units
+    #[arg(long, default_value_t = DEFAULT_WIDTH as f64, value_parser = parse_positive)]
+    width: f64,
+
+    /// The initial height of the windows in --units
+    #[arg(long, default_value_t = DEFAULT_HEIGHT as f64, value_parser = parse_positive)]
+    height: f64,
+
+    /// The unit of --width and --height. Inches are converted with --dpi.
+    #[arg(long, value_enum, default_value_t = SizeUnit::Px)]
+    units: SizeUnit,
+
+    /// The title of the windows
+    #[arg(long, default_value = DEFAULT_TITLE)]
+    title: String,
+
+    /// The background color of the pages in hex (e.g. "fff" or "ffffff00")
+    /// when NewPage doesn't specify it
+    #[arg(long, default_value = "f5f5f5", value_parser = parse_color)]
+    bg: Color,
+
+    /// Print more logs: -v for the requests, -vv for everything. RUST_LOG
+    /// takes precedence if set.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// The antialiasing method
+    #[arg(long, value_enum, default_value_t = Antialiasing::Msaa16)]
+    antialiasing: Antialiasing,
+
+    /// Don't wait for the vertical blank to present the frames
+    #[arg(long)]
+    no_vsync: bool,
+
+    /// The preferred adapter: "low-power", "high-performance", "software", or
+    /// a part of the adapter name
+    #[arg(long)]
+    adapter: Option<AdapterPreference>,
+}
+
+fn spawn_server(
+    listener: Listener,
+    event_sender: EventSender,
+    notifier: broadcast::Sender<DeviceNotification>,
+    dpi: f64,
+    open_devices: OpenDevices,
+) {
+    let greeter = VelloGraphicsDevice::new(event_sender, notifier, dpi, open_devices);
+
+    tokio::spawn(async move {
+        let router = Server::builder().add_service(GraphicsDeviceServer::new(greeter));
+        let result = match listener {
+            Listener::Tcp(listener) => {
+                router
+                    .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+                    .await
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                router
+                    .serve_with_incoming(tokio_stream::wrappers::UnixListenerStream::new(listener))
+                    .await
+            }
+        };
+        // TODO: propagate error via EventLoopProxy
+        if let Err(e) = result {
+            log::error!("Failed to serve: {e}");
+        }
+    });
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Cli::parse();
+
+    // The other crates (e.g. wgpu) are too verbose except for warnings
+    let level = match args.verbose {
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Warn)
+        .filter_module("vellogd_server", level)
+        .parse_default_env()
+        .init();
+
+    let pixels_per_unit = match args.units {
+        SizeUnit::Px => 1.0,
+        SizeUnit::In => args.dpi,
+    };
+    let device_config = DeviceConfig {
+        size: (
+            (args.width * pixels_per_unit).round() as u32,
+            (args.height * pixels_per_unit).round() as u32,
+        ),
+        title: args.title,
+        background_color: args.bg,
+    };
+
     let mut app = VelloApp {
-        context: RenderContext::new(),
+        context: GpuContext::new(args.adapter.clone().unwrap_or_default()),
         renderers: vec![],
-        state: RenderState::Suspended(None),
-        scene: Scene::new(),
-        background_color: Color::WHITE_SMOKE,
+        devices: HashMap::from([(0, DeviceState::new(0, device_config.clone()))]),
+        window_ids: HashMap::new(),
         font_ctx: parley::FontContext::new(),
+        offscreen_device: None,
+        notifier: broadcast::channel(NOTIFICATION_CAPACITY).0,
+        redraw_delay: Duration::from_millis(args.redraw_delay),
+        render_options: RenderOptions {
+            antialiasing: args.antialiasing,
+            vsync: !args.no_vsync,
+        },
+        dpi: args.dpi,
+        device_config,
+        // The device 0 is always open
+        open_devices: Arc::new(Mutex::new(HashSet::from([0]))),
     };
 
-    let event_loop = EventLoop::<UserEvent>::with_user_event().build()?;
-    let event_loop_proxy = event_loop.create_proxy();
-
-    let addr = "[::1]:50051".parse()?;
-    let greeter = VelloGraphicsDevice::new(event_loop_proxy);
+    // Fail early if no adapter matches
+    if let Some(adapter) = &args.adapter {
+        pollster::block_on(render_options::select_adapter(
+            &app.context.instance,
+            adapter,
+            None,
+        ))?;
+    }
 
-    tokio::spawn(async move {
-        // TODO: propagate error via EventLoopProxy
-        let _res = Server::builder()
-            .add_service(GraphicsDeviceServer::new(greeter))
-            .serve(addr)
-            .await;
-    });
+    // The socket file is removed on drop, i.e. when the server exits normally
+    #[cfg(unix)]
+    let (listener, _socket_file) = match &args.unix {
+        Some(path) => {
+            let (listener, socket_file) = listener::bind_unix(path)?;
+            (listener, Some(socket_file))
+        }
+        None => (Listener::bind_tcp(args.address).await?, None),
+    };
+    #[cfg(not(unix))]
+    let listener = Listener::bind_tcp(args.address).await?;
+
+    let address = listener.client_address()?;
+    log::info!("Listening on {address}");
+    // This is removed on drop, i.e. when the server exits normally
+    let discovery_file = discovery::DiscoveryFile::create(&discovery::ServerEntry::new(address));
+    match &discovery_file {
+        Ok(file) => log::info!("Wrote the discovery file to {}", file.path().display()),
+        // The clients can still connect with the address
+        Err(e) => log::warn!("Failed to write the discovery file: {e}"),
+    }
 
-    event_loop.run_app(&mut app)?;
+    if args.headless {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        spawn_server(
+            listener,
+            EventSender::Headless(sender),
+            app.notifier.clone(),
+            app.dpi,
+            app.open_devices.clone(),
+        );
+
+        app.run_headless(receiver);
+    } else {
+        let event_loop = EventLoop::<DeviceEvent>::with_user_event().build()?;
+        spawn_server(
+            listener,
+            EventSender::EventLoop(event_loop.create_proxy()),
+            app.notifier.clone(),
+            app.dpi,
+            app.open_devices.clone(),
+        );
+
+        event_loop.run_app(&mut app)?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,143 @@
+// Rendering to a texture instead of a window surface. This is based on the
+// headless example on linebender/vello (examples/headless/src/main.rs).
+
+use vello::{wgpu, RenderParams, Renderer, Scene};
+
+use crate::{
+    gpu::GpuDevice,
+    render_options::{self, AdapterPreference, Antialiasing},
+    validation::ValidationError,
+};
+
+/// A device that is not tied to any window (used in headless mode).
+pub(crate) struct OffscreenDevice {
+    pub(crate) adapter_info: wgpu::AdapterInfo,
+    pub(crate) device: wgpu::Device,
+    pub(crate) queue: wgpu::Queue,
+    pub(crate) renderer: Renderer,
+    // Whether the renderer uses vello's CPU shaders
+    pub(crate) use_cpu: bool,
+}
+
+impl OffscreenDevice {
+    pub(crate) async fn new(
+        instance: &wgpu::Instance,
+        adapter_preference: &AdapterPreference,
+        antialiasing: Antialiasing,
+    ) -> anyhow::Result<Self> {
+        let adapter = render_options::select_adapter(instance, adapter_preference, None).await?;
+        let adapter_info = adapter.get_info();
+        let GpuDevice { device, queue, .. } =
+            GpuDevice::new(adapter, adapter_preference.clone()).await?;
+
+        let (renderer, use_cpu) =
+            render_options::create_renderer(&device, None, antialiasing, false)?;
+
+        Ok(Self {
+            adapter_info,
+            device,
+            queue,
+            renderer,
+            use_cpu,
+        })
+    }
+}
+
+/// Renders the scene into a texture and reads it back as RGBA8 pixels (not
+/// premultiplied). An image larger than the device's limit is rejected with
+/// a ValidationError.
+pub(crate) fn render_to_rgba(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    renderer: &mut Renderer,
+    scene: &Scene,
+    params: &RenderParams,
+) -> anyhow::Result<Vec<u8>> {
+    let width = params.width;
+    let height = params.height;
+    if width == 0 || height == 0 {
+        anyhow::bail!("invalid image size: {width} x {height}");
+    }
+    let max_size = device.limits().max_texture_dimension_2d;
+    for (field, value) in [("width", width), ("height", height)] {
+        if value > max_size {
+            let message = format!("{value} pixels after scaling exceeds the maximum {max_size}");
+            return Err(ValidationError::new(field, message).into());
+        }
+    }
+
+    // Catch wgpu's errors so that they don't reach the uncaptured error
+    // handler, which panics
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+
+    let size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+    let target = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Offscreen target texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+
+    if let Err(e) = renderer.render_to_texture(device, queue, scene, &view, params) {
+        // Don't leave the error scope pushed
+        let _ = pollster::block_on(device.pop_error_scope());
+        anyhow::bail!("failed to render: {e}");
+    }
+
+    // The rows of the buffer need to be aligned
+    let padded_byte_width = (width * 4).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Offscreen readback buffer"),
+        size: padded_byte_width as u64 * height as u64,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Copy out buffer"),
+    });
+    encoder.copy_texture_to_buffer(
+        target.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_byte_width),
+                rows_per_image: None,
+            },
+        },
+        size,
+    );
+    queue.submit([encoder.finish()]);
+    if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+        anyhow::bail!("failed to render: {e}");
+    }
+
+    let buffer_slice = buffer.slice(..);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::Maintain::Wait);
+    receiver.recv()??;
+
+    let data = buffer_slice.get_mapped_range();
+    let mut result = Vec::with_capacity((width * height * 4) as usize);
+    for row in 0..height {
+        let start = (row * padded_byte_width) as usize;
+        result.extend_from_slice(&data[start..start + (width * 4) as usize]);
+    }
+    drop(data);
+    buffer.unmap();
+
+    Ok(result)
+}
@@ -0,0 +1,197 @@
+// The options of the renderer that can be specified by the command-line flags
+// at startup and changed by SetRenderOptions at runtime.
+
+use std::num::NonZeroUsize;
+
+use vello::{wgpu, AaConfig, AaSupport, Renderer, RendererOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Antialiasing {
+    Area,
+    Msaa8,
+    Msaa16,
+}
+
+impl Antialiasing {
+    pub(crate) fn aa_config(self) -> AaConfig {
+        match self {
+            Antialiasing::Area => AaConfig::Area,
+            Antialiasing::Msaa8 => AaConfig::Msaa8,
+            Antialiasing::Msaa16 => AaConfig::Msaa16,
+        }
+    }
+
+    // The renderer compiles the shaders only for the method in use
+    pub(crate) fn aa_support(self) -> AaSupport {
+        AaSupport {
+            area: self == Antialiasing::Area,
+            msaa8: self == Antialiasing::Msaa8,
+            msaa16: self == Antialiasing::Msaa16,
+        }
+    }
+
+    /// Converts from the protocol's enum. None means "unspecified".
+    pub(crate) fn from_proto(value: vellogd_protocol::AntialiasingMethod) -> Option<Self> {
+        use vellogd_protocol::AntialiasingMethod;
+
+        match value {
+            AntialiasingMethod::Unspecified => None,
+            AntialiasingMethod::Area => Some(Antialiasing::Area),
+            AntialiasingMethod::Msaa8 => Some(Antialiasing::Msaa8),
+            AntialiasingMethod::Msaa16 => Some(Antialiasing::Msaa16),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RenderOptions {
+    pub(crate) antialiasing: Antialiasing,
+    pub(crate) vsync: bool,
+}
+
+impl RenderOptions {
+    // Note: Auto* modes are always supported, unlike the others (e.g. Mailbox)
+    pub(crate) fn present_mode(&self) -> wgpu::PresentMode {
+        if self.vsync {
+            wgpu::PresentMode::AutoVsync
+        } else {
+            wgpu::PresentMode::AutoNoVsync
+        }
+    }
+}
+
+/// Creates a renderer. If the GPU shaders are not usable (e.g. on a VM with a
+/// limited GPU), this falls back to vello's CPU shaders. If use_cpu is true,
+/// the CPU shaders are used from the start.
+///
+/// Returns the renderer and whether it uses the CPU shaders.
+pub(crate) fn create_renderer(
+    device: &wgpu::Device,
+    surface_format: Option<wgpu::TextureFormat>,
+    antialiasing: Antialiasing,
+    use_cpu: bool,
+) -> anyhow::Result<(Renderer, bool)> {
+    if !use_cpu {
+        match try_create_renderer(device, surface_format, antialiasing, false) {
+            Ok(renderer) => return Ok((renderer, false)),
+            Err(e) => log::warn!("Failed to create renderer: {e}. Falling back to the CPU shaders"),
+        }
+    }
+
+    let renderer = try_create_renderer(device, surface_format, antialiasing, true)?;
+    Ok((renderer, true))
+}
+
+fn try_create_renderer(
+    device: &wgpu::Device,
+    surface_format: Option<wgpu::TextureFormat>,
+    antialiasing: Antialiasing,
+    use_cpu: bool,
+) -> anyhow::Result<Renderer> {
+    // Without the error scope, wgpu's errors (e.g. failure of shader
+    // compilation) are passed to the uncaptured error handler, which panics.
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let result = Renderer::new(
+        device,
+        RendererOptions {
+            surface_format,
+            use_cpu,
+            antialiasing_support: antialiasing.aa_support(),
+            num_init_threads: NonZeroUsize::new(1),
+        },
+    );
+    let error = pollster::block_on(device.pop_error_scope());
+
+    let renderer = result.map_err(|e| anyhow::anyhow!("{e}"))?;
+    if let Some(e) = error {
+        anyhow::bail!("{e}");
+    }
+    Ok(renderer)
+}
+
+/// Which adapter to use. Parsed from "default", "low-power",
+/// "high-performance", "software" (e.g. llvmpipe), or a part of the adapter
+/// name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) enum AdapterPreference {
+    // wgpu's default, which respects wgpu's environment variables (e.g.
+    // WGPU_ADAPTER_NAME) set by the user
+    #[default]
+    Default,
+    LowPower,
+    HighPerformance,
+    Software,
+    // Lowercased
+    Name(String),
+}
+
+impl From<&str> for AdapterPreference {
+    fn from(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "" | "default" => AdapterPreference::Default,
+            "low-power" => AdapterPreference::LowPower,
+            "high-performance" => AdapterPreference::HighPerformance,
+            "software" => AdapterPreference::Software,
+            name => AdapterPreference::Name(name.to_string()),
+        }
+    }
+}
+
+/// Chooses the adapter by the preference. If compatible_surface is given, the
+/// adapter must be able to present to it.
+pub(crate) async fn select_adapter(
+    instance: &wgpu::Instance,
+    preference: &AdapterPreference,
+    compatible_surface: Option<&wgpu::Surface<'_>>,
+) -> anyhow::Result<wgpu::Adapter> {
+    let request_adapter = |power_preference, force_fallback_adapter| {
+        instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference,
+            force_fallback_adapter,
+            compatible_surface,
+        })
+    };
+    let find_adapter = |predicate: &dyn Fn(&wgpu::AdapterInfo) -> bool| {
+        instance
+            .enumerate_adapters(wgpu::Backends::all())
+            .into_iter()
+            .find(|adapter| {
+                predicate(&adapter.get_info())
+                    && compatible_surface.is_none_or(|s| adapter.is_surface_supported(s))
+            })
+    };
+
+    let adapter = match preference {
+        AdapterPreference::Default => {
+            match wgpu::util::initialize_adapter_from_env_or_default(instance, compatible_surface)
+                .await
+            {
+                Some(adapter) => Some(adapter),
+                // If there's no GPU (e.g. on CI), try the software adapter
+                // (e.g. WARP on Windows, llvmpipe on Linux)
+                None => request_adapter(wgpu::PowerPreference::default(), true).await,
+            }
+        }
+        AdapterPreference::LowPower => {
+            request_adapter(wgpu::PowerPreference::LowPower, false).await
+        }
+        AdapterPreference::HighPerformance => {
+            request_adapter(wgpu::PowerPreference::HighPerformance, false).await
+        }
+        AdapterPreference::Software => {
+            find_adapter(&|info| info.device_type == wgpu::DeviceType::Cpu)
+        }
+        AdapterPreference::Name(name) => {
+            find_adapter(&|info| info.name.to_lowercase().contains(name.as_str()))
+        }
+    };
+
+    adapter.ok_or_else(|| {
+        let available = instance
+            .enumerate_adapters(wgpu::Backends::all())
+            .iter()
+            .map(|adapter| adapter.get_info().name)
+            .collect::<Vec<_>>();
+        anyhow::anyhow!("no adapter matches {preference:?} (available: {available:?})")
+    })
+}
@@ -0,0 +1,179 @@
+// Text layout. The glyphs are resolved when the text is drawn, so that the
+// result can be reused to rebuild the scene and to export.
+
+use vello::{
+    kurbo::{Affine, BezPath, Point},
+    skrifa::{
+        instance::{LocationRef, NormalizedCoord, Size},
+        outline::{DrawSettings, OutlinePen},
+        FontRef, GlyphId, MetadataProvider,
+    },
+};
+
+/// A run of glyphs that share the same font.
+#[derive(Debug, Clone)]
+pub(crate) struct GlyphRun {
+    pub(crate) font: vello::peniko::Font,
+    pub(crate) font_size: f32,
+    pub(crate) normalized_coords: Vec<NormalizedCoord>,
+    pub(crate) glyphs: Vec<vello::Glyph>,
+}
+
+impl GlyphRun {
+    /// Converts the glyphs to their outlines. `transform` is applied to the
+    /// glyph positions.
+    pub(crate) fn to_path(&self, transform: Affine) -> BezPath {
+        let mut path = BezPath::new();
+
+        let Ok(font) = FontRef::from_index(self.font.data.data(), self.font.index) else {
+            return path;
+        };
+        let outlines = font.outline_glyphs();
+        let location = LocationRef::new(&self.normalized_coords);
+
+        for glyph in &self.glyphs {
+            let Some(outline) = outlines.get(GlyphId::new(glyph.id)) else {
+                continue;
+            };
+
+            // Note: the y axis of the outlines points upward
+            let mut pen = PathPen {
+                path: &mut path,
+                transform: transform
+                    * Affine::translate((glyph.x as f64, glyph.y as f64))
+                    * Affine::FLIP_Y,
+            };
+            let settings = DrawSettings::unhinted(Size::new(self.font_size), location);
+            // Just skip the glyphs that fail to draw
+            let _ = outline.draw(settings, &mut pen);
+        }
+
+        path
+    }
+}
+
+struct PathPen<'a> {
+    path: &'a mut BezPath,
+    transform: Affine,
+}
+
+impl PathPen<'_> {
+    fn point(&self, x: f32, y: f32) -> Point {
+        self.transform * Point::new(x as f64, y as f64)
+    }
+}
+
+impl OutlinePen for PathPen<'_> {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let p = self.point(x, y);
+        self.path.move_to(p);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let p = self.point(x, y);
+        self.path.line_to(p);
+    }
+
+    fn quad_to(&mut self, cx0: f32, cy0: f32, x: f32, y: f32) {
+        let p1 = self.point(cx0, cy0);
+        let p2 = self.point(x, y);
+        self.path.quad_to(p1, p2);
+    }
+
+    fn curve_to(&mut self, cx0: f32, cy0: f32, cx1: f32, cy1: f32, x: f32, y: f32) {
+        let p1 = self.point(cx0, cy0);
+        let p2 = self.point(cx1, cy1);
+        let p3 = self.point(x, y);
+        self.path.curve_to(p1, p2, p3);
+    }
+
+    fn close(&mut self) {
+        self.path.close_path();
+    }
+}
+
+/// Lays out the text and returns the glyph runs and the width of the text.
+pub(crate) fn layout(
+    font_ctx: &mut parley::FontContext,
+    text: &str,
+    size: f32,
+    lineheight: f32,
+) -> (Vec<GlyphRun>, f32) {
+    // Note: parley is probably a little bit overkill, but it seems
+    // this is the only interface.
+    let mut layout_ctx: parley::LayoutContext<vello::peniko::Brush> = parley::LayoutContext::new();
+    let mut layout_builder = layout_ctx.ranged_builder(font_ctx, text, 1.0); // TODO: should scale be configurable?
+    layout_builder.push_default(&parley::StyleProperty::FontSize(size));
+    layout_builder.push_default(&parley::StyleProperty::LineHeight(lineheight));
+    layout_builder.push_default(&parley::StyleProperty::FontStack(
+        parley::FontStack::Source("system-iu"), // TODO: specify family
+    ));
+    // TODO: use build_into() to reuse a Layout?
+    let mut layout = layout_builder.build(text);
+    layout.break_all_lines(None); // It seems this is mandatory, otherwise no text is drawn. Why?
+    layout.align(None, parley::Alignment::Start);
+
+    let mut glyph_runs = Vec::new();
+
+    for line in layout.lines() {
+        let vadj = line.metrics().ascent * 0.5;
+        for item in line.items() {
+            // ignore inline box
+            let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item else {
+                continue;
+            };
+
+            let mut x = glyph_run.offset();
+            let y = glyph_run.baseline() - vadj;
+            let run = glyph_run.run();
+
+            let font = run.font();
+            let font_size = run.font_size();
+
+            // TODO:  It seems this is to handle italic. Is this necessary?
+            //
+            // https://github.com/linebender/parley/blob/be9e9ab3fc3fe92b3887048d5123c963cffac3d5/examples/vello_editor/src/text.rs#L364-L366
+            // https://docs.rs/kurbo/latest/kurbo/struct.Affine.html#method.skew
+            //
+            // let glyph_xform = run.synthesis().skew().map(|angle| {
+            //     vello::kurbo::Affine::skew(angle.to_radians().tan() as f64, 0.0)
+            // });
+
+            let normalized_coords = run
+                .normalized_coords()
+                .iter()
+                .map(|coord| NormalizedCoord::from_bits(*coord))
+                .collect::<Vec<_>>();
+
+            // TODO: vello and parley uses different versions of font
+            let font = {
+                let raw = font.clone().data.into_raw_parts();
+                let data = vello::peniko::Blob::from_raw_parts(raw.0, raw.1);
+                vello::peniko::Font::new(data, font.index)
+            };
+
+            let glyphs = glyph_run
+                .glyphs()
+                .map(|g| {
+                    let gx = x + g.x;
+                    let gy = y - g.y;
+                    x += g.advance;
+                    vello::Glyph {
+                        id: g.id as _,
+                        x: gx,
+                        y: gy,
+                    }
+                })
+                .collect();
+
+            glyph_runs.push(GlyphRun {
+                font,
+                font_size,
+                normalized_coords,
+                glyphs,
+            });
+        }
+    }
+
+    (glyph_runs, layout.width())
+}
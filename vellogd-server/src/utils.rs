@@ -1,81 +1,602 @@
+use crate::{
+    validation::{self, ValidationError},
+    ColorSpace, LineWidthUnit,
+};
+
+// cf. https://github.com/r-devel/r-svn/blob/6ad1e0f2702fd0308e4f3caac2e22541d014ab6a/src/include/R_ext/GraphicsEngine.h#L413C1-L419C50
+const LTY_BLANK: i32 = -1;
+
+// The default of par(lmitre) in R
+const DEFAULT_MITER_LIMIT: f64 = 10.0;
+
 impl crate::StrokeParams {
-    pub fn from_request(value: crate::StrokeParameters) -> Self {
+    /// Returns None if the line is not drawn at all (LTY_BLANK). The width is
+    /// converted to the pixels of the device with the DPI.
+    pub fn from_request(
+        value: crate::StrokeParameters,
+        dpi: f64,
+    ) -> Result<Option<Self>, ValidationError> {
         // cf. https://github.com/r-devel/r-svn/blob/6ad1e0f2702fd0308e4f3caac2e22541d014ab6a/src/include/R_ext/GraphicsEngine.h#L183-L187
         let join = match value.join {
             1 => vello::kurbo::Join::Round,
             2 => vello::kurbo::Join::Miter,
             3 => vello::kurbo::Join::Bevel,
-            v => panic!("invalid join value: {v}"),
+            v => {
+                return Err(ValidationError::new(
+                    "join",
+                    format!("unknown value {v} (must be 1, 2, or 3)"),
+                ))
+            }
         };
         // cf. https://github.com/r-devel/r-svn/blob/6ad1e0f2702fd0308e4f3caac2e22541d014ab6a/src/include/R_ext/GraphicsEngine.h#L183-L187
         let cap = match value.cap {
             1 => vello::kurbo::Cap::Round,
-            2 => vello::kurbo::Cap::Round,
-            3 => vello::kurbo::Cap::Round,
-            v => panic!("invalid cap value: {v}"),
+            2 => vello::kurbo::Cap::Butt,
+            3 => vello::kurbo::Cap::Square,
+            v => {
+                return Err(ValidationError::new(
+                    "cap",
+                    format!("unknown value {v} (must be 1, 2, or 3)"),
+                ))
+            }
+        };
+        let width_unit = LineWidthUnit::try_from(value.width_unit).map_err(|_| {
+            ValidationError::new("width_unit", format!("unknown value {}", value.width_unit))
+        })?;
+        // R's lwd = 1 is 1/96 inch, and 1 point is 1/72 inch
+        let pixels_per_unit = match width_unit {
+            LineWidthUnit::Pixel => 1.0,
+            LineWidthUnit::Lwd => dpi / 96.0,
+            LineWidthUnit::Point => dpi / 72.0,
+        };
+        // Note: the dash lengths are relative to this, so they are also converted
+        let width = validation::non_negative("width", value.width)? * pixels_per_unit;
+        // R requires the miter limit to be at least 1 (cf. ?par)
+        let miter_limit = if value.miter_limit == 0.0 {
+            DEFAULT_MITER_LIMIT
+        } else {
+            let miter_limit = validation::finite("miter_limit", value.miter_limit)?;
+            if miter_limit < 1.0 {
+                return Err(ValidationError::new(
+                    "miter_limit",
+                    format!("must be at least 1, but got {miter_limit}"),
+                ));
+            }
+            miter_limit
         };
 
-        // cf. https://github.com/r-devel/r-svn/blob/6ad1e0f2702fd0308e4f3caac2e22541d014ab6a/src/include/R_ext/GraphicsEngine.h#L413C1-L419C50
-        //
-        // TODO: I need to figure out the conversion logic. What is this `& 15`...?
-        //
-        // https://github.com/r-devel/r-svn/blob/6ad1e0f2702fd0308e4f3caac2e22541d014ab6a/src/modules/X11/devX11.c#L1224
-        // https://github.com/r-lib/ragg/blob/6e8bfd1264dfaa36aa6f92592e13a1169986e7b9/src/AggDevice.h#L195C8-L205
-        let dash_pattern = match value.linetype {
-            -1 => Default::default(), // TODO
-            0 => Default::default(),
-            49 => vello::kurbo::Dashes::from_const([1.0, 1.0, 1.0, 1.0]), // LTY_DOTTED	1 + (3<<4)
-            68 => vello::kurbo::Dashes::from_const([1.0, 1.0, 1.0, 1.0]), // LTY_DASHED	4 + (4<<4)
-            _ => Default::default(),
+        let Some(dash_pattern) = lty_to_dashes(value.linetype, width) else {
+            return Ok(None);
         };
-        Self {
-            color: u32_to_color(value.color),
+
+        let color = color_from_request(value.color, value.color_rgba)
+            .map_err(|e| e.in_field("color_rgba"))?;
+
+        Ok(Some(Self {
+            color,
             stroke: vello::kurbo::Stroke {
-                width: value.width,
+                width,
                 join,
-                miter_limit: value.miter_limit,
+                miter_limit,
                 start_cap: cap,
                 end_cap: cap,
                 dash_pattern,
                 dash_offset: 0.0,
             },
-        }
+        }))
     }
 }
 
+/// Converts R's line type into the dash pattern. Returns None for LTY_BLANK.
+///
+/// Except for LTY_BLANK and LTY_SOLID (0), the line type is packed 4-bit
+/// lengths of dashes and gaps alternately, from the lowest bits. The lengths
+/// continue until a zero (up to 8). For example, LTY_DASHED is 0x44 and
+/// LTY_DOTDASH is 0x3431.
+///
+/// cf. https://github.com/r-devel/r-svn/blob/6ad1e0f2702fd0308e4f3caac2e22541d014ab6a/src/modules/X11/devX11.c#L1224
+/// cf. https://github.com/r-lib/ragg/blob/6e8bfd1264dfaa36aa6f92592e13a1169986e7b9/src/AggDevice.h#L195C8-L205
+fn lty_to_dashes(lty: i32, width: f64) -> Option<vello::kurbo::Dashes> {
+    if lty == LTY_BLANK {
+        return None;
+    }
+
+    // Like X11 and ragg, the lengths are in the unit of the line width, but
+    // thin lines use 1 so that the dashes are still visible.
+    let unit = width.max(1.0);
+
+    // Note: e.g. "88888888" is negative as i32, so treat it as unsigned
+    let lty = lty as u32;
+    let dashes = (0..8)
+        .map(|i| (lty >> (i * 4)) & 15)
+        .take_while(|&len| len != 0)
+        .map(|len| len as f64 * unit)
+        .collect();
+
+    // LTY_SOLID results in an empty pattern, which means a solid line
+    Some(dashes)
+}
+
 impl crate::FillParams {
-    pub fn from_request(color: u32) -> Self {
-        Self {
-            color: u32_to_color(color),
-        }
+    /// Returns None if neither color is specified.
+    pub fn from_request(
+        color: Option<u32>,
+        color_rgba: Option<vellogd_protocol::Color>,
+    ) -> Result<Option<Self>, ValidationError> {
+        let color = optional_color_from_request(color, color_rgba)?;
+        Ok(color.map(|color| Self { color }))
     }
 }
 
 pub(crate) fn u32_to_color(x: u32) -> vello::peniko::Color {
-    let [r, g, b, a] = x.to_ne_bytes();
+    let [r, g, b, a] = vellogd_protocol::unpack_rgba(x);
     vello::peniko::Color::rgba8(r, g, b, a)
 }
 
-// Note: BezPath allows more than lines, but R's graphics API currently uses lines only.
-pub(crate) fn xy_to_path(x: Vec<f64>, y: Vec<f64>, close: bool) -> vello::kurbo::BezPath {
-    let mut path = vello::kurbo::BezPath::new();
+/// Converts the color specified either in R's packed format or by the Color
+/// message. The latter takes precedence. The errors of the Color message are
+/// relative to the message.
+pub(crate) fn color_from_request(
+    packed: u32,
+    rgba: Option<vellogd_protocol::Color>,
+) -> Result<vello::peniko::Color, ValidationError> {
+    match rgba {
+        Some(rgba) => color_message_to_color(rgba),
+        None => Ok(u32_to_color(packed)),
+    }
+}
+
+/// The same as color_from_request(), but the color can be omitted.
+pub(crate) fn optional_color_from_request(
+    packed: Option<u32>,
+    rgba: Option<vellogd_protocol::Color>,
+) -> Result<Option<vello::peniko::Color>, ValidationError> {
+    match (packed, rgba) {
+        (_, Some(rgba)) => color_message_to_color(rgba).map(Some),
+        (Some(packed), None) => Ok(Some(u32_to_color(packed))),
+        (None, None) => Ok(None),
+    }
+}
 
-    let x_iter = x.into_iter();
-    let y_iter = y.into_iter();
-    let mut points = x_iter.zip(y_iter);
-    if let Some(first) = points.next() {
-        path.move_to(vello::kurbo::Point::new(first.0, first.1));
+fn color_message_to_color(
+    value: vellogd_protocol::Color,
+) -> Result<vello::peniko::Color, ValidationError> {
+    let color_space = ColorSpace::try_from(value.color_space).map_err(|_| {
+        ValidationError::new(
+            "color_space",
+            format!("unknown value {}", value.color_space),
+        )
+    })?;
+
+    let component = |field: &str, v: f32| {
+        let v = validation::finite(field, v)?;
+        if !(0.0..=1.0).contains(&v) {
+            return Err(ValidationError::new(
+                field,
+                format!("must be between 0 and 1, but got {v}"),
+            ));
+        }
+        Ok(v)
+    };
+    let r = component("r", value.r)?;
+    let g = component("g", value.g)?;
+    let b = component("b", value.b)?;
+    let a = component("a", value.a)?;
+
+    // The renderer works in sRGB
+    let [r, g, b] = match color_space {
+        ColorSpace::Srgb => [r, g, b],
+        ColorSpace::LinearSrgb => [r, g, b].map(linear_to_srgb),
+    };
+
+    let to_u8 = |v: f32| (v * 255.0).round() as u8;
+    Ok(vello::peniko::Color::rgba8(
+        to_u8(r),
+        to_u8(g),
+        to_u8(b),
+        to_u8(a),
+    ))
+}
+
+// cf. https://www.w3.org/TR/css-color-4/#color-conversion-code
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
     } else {
-        return path;
+        1.055 * v.powf(1.0 / 2.4) - 0.055
     }
+}
 
-    for (x, y) in points {
-        path.line_to(vello::kurbo::Point::new(x, y));
+// Note: BezPath allows more than lines, but R's graphics API currently uses lines only.
+//
+// R uses NA (NaN) in the coordinates to break the line, so a non-finite point
+// ends the current subpath and the next finite point starts a new one. If
+// close is true, each subpath is closed, i.e. a polygon with NA is drawn as
+// separate pieces.
+pub(crate) fn xy_to_path(
+    x: Vec<f64>,
+    y: Vec<f64>,
+    close: bool,
+) -> Result<vello::kurbo::BezPath, ValidationError> {
+    if x.len() != y.len() {
+        return Err(ValidationError::new(
+            "y",
+            format!(
+                "must have the same length as x ({}), but got {}",
+                x.len(),
+                y.len()
+            ),
+        ));
+    }
+
+    let mut path = vello::kurbo::BezPath::new();
+    let mut in_subpath = false;
+
+    for (x, y) in x.into_iter().zip(y) {
+        if !x.is_finite() || !y.is_finite() {
+            if in_subpath && close {
+                path.close_path();
+            }
+            in_subpath = false;
+            continue;
+        }
+
+        let point = vello::kurbo::Point::new(x, y);
+        if in_subpath {
+            path.line_to(point);
+        } else {
+            path.move_to(point);
+            in_subpath = true;
+        }
     }
 
-    if close {
+    if in_subpath && close {
         path.close_path();
     }
 
-    path
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StrokeParams;
+
+    // R's line type string (e.g. "1343") as the packed integer. The first
+    // digit goes to the lowest bits.
+    fn lty_from_str(s: &str) -> i32 {
+        s.chars()
+            .rev()
+            .fold(0, |lty, c| lty << 4 | c.to_digit(16).unwrap()) as i32
+    }
+
+    fn dashes(lty: i32, width: f64) -> Vec<f64> {
+        lty_to_dashes(lty, width).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_lty_to_dashes_builtin() {
+        assert_eq!(dashes(0, 1.0), Vec::<f64>::new()); // solid
+        assert_eq!(dashes(0x44, 1.0), [4.0, 4.0]); // dashed
+        assert_eq!(dashes(0x31, 1.0), [1.0, 3.0]); // dotted
+        assert_eq!(dashes(0x3431, 1.0), [1.0, 3.0, 4.0, 3.0]); // dotdash
+        assert_eq!(dashes(0x37, 1.0), [7.0, 3.0]); // longdash
+        assert_eq!(dashes(0x2622, 1.0), [2.0, 2.0, 6.0, 2.0]); // twodash
+    }
+
+    #[test]
+    fn test_lty_to_dashes_hex_string() {
+        assert_eq!(dashes(lty_from_str("44"), 1.0), [4.0, 4.0]);
+        assert_eq!(dashes(lty_from_str("1343"), 1.0), [1.0, 3.0, 4.0, 3.0]);
+    }
+
+    #[test]
+    fn test_lty_to_dashes_above_i32_max() {
+        let lty = lty_from_str("88888888");
+        assert_eq!(lty as u32, 0x88888888);
+        assert_eq!(dashes(lty, 1.0), [8.0; 8]);
+    }
+
+    #[test]
+    fn test_lty_to_dashes_blank() {
+        assert!(lty_to_dashes(LTY_BLANK, 1.0).is_none());
+    }
+
+    #[test]
+    fn test_lty_to_dashes_width() {
+        assert_eq!(dashes(0x44, 2.5), [10.0, 10.0]);
+        // Thin lines use the unit of 1
+        assert_eq!(dashes(0x44, 0.5), [4.0, 4.0]);
+        assert_eq!(dashes(0x44, 0.0), [4.0, 4.0]);
+    }
+
+    fn color(r: f32, g: f32, b: f32, a: f32, color_space: ColorSpace) -> Result<[u8; 4], String> {
+        let value = vellogd_protocol::Color {
+            r,
+            g,
+            b,
+            a,
+            color_space: color_space.into(),
+        };
+        color_message_to_color(value)
+            .map(|c| [c.r, c.g, c.b, c.a])
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_color_message_to_color_srgb() {
+        assert_eq!(
+            color(1.0, 0.5, 0.0, 0.2, ColorSpace::Srgb),
+            Ok([255, 128, 0, 51])
+        );
+    }
+
+    #[test]
+    fn test_color_message_to_color_linear_srgb() {
+        // The ends are kept, and linear 0.5 is about 0.735 in sRGB. The alpha
+        // is not converted.
+        assert_eq!(
+            color(0.0, 0.5, 1.0, 0.5, ColorSpace::LinearSrgb),
+            Ok([0, 188, 255, 128])
+        );
+        // The linear segment near 0
+        assert_eq!(
+            color(0.001, 0.0, 0.0, 1.0, ColorSpace::LinearSrgb),
+            Ok([3, 0, 0, 255])
+        );
+    }
+
+    #[test]
+    fn test_color_message_to_color_out_of_range() {
+        for (v, field) in [
+            (-0.1, "r"),
+            (1.1, "g"),
+            (f32::NAN, "b"),
+            (f32::INFINITY, "a"),
+        ] {
+            let mut rgba = [0.5; 4];
+            rgba["rgba".find(field).unwrap()] = v;
+            let [r, g, b, a] = rgba;
+            let err = color(r, g, b, a, ColorSpace::Srgb).unwrap_err();
+            assert!(err.starts_with(&format!("invalid {field}:")), "{err}");
+        }
+
+        let value = vellogd_protocol::Color {
+            color_space: 100,
+            ..Default::default()
+        };
+        let err = color_message_to_color(value).unwrap_err().to_string();
+        assert!(err.starts_with("invalid color_space:"), "{err}");
+    }
+
+    fn stroke_params(cap: u32, miter_limit: f64) -> Result<StrokeParams, ValidationError> {
+        let request = crate::StrokeParameters {
+            width: 1.0,
+            join: 1,
+            cap,
+            miter_limit,
+            ..Default::default()
+        };
+        StrokeParams::from_request(request, 72.0).map(Option::unwrap)
+    }
+
+    #[test]
+    fn test_stroke_params_cap() {
+        use vello::kurbo::Cap;
+
+        // R's GE_ROUND_CAP, GE_BUTT_CAP, and GE_SQUARE_CAP
+        for (cap, expected) in [(1, Cap::Round), (2, Cap::Butt), (3, Cap::Square)] {
+            let stroke = stroke_params(cap, 0.0).unwrap().stroke;
+            assert_eq!(stroke.start_cap, expected);
+            assert_eq!(stroke.end_cap, expected);
+        }
+        for cap in [0, 4] {
+            let err = stroke_params(cap, 0.0).unwrap_err();
+            assert!(err.to_string().starts_with("invalid cap:"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_stroke_params_miter_limit() {
+        assert_eq!(stroke_params(1, 0.0).unwrap().stroke.miter_limit, 10.0);
+        assert_eq!(stroke_params(1, 1.0).unwrap().stroke.miter_limit, 1.0);
+        assert_eq!(stroke_params(1, 4.0).unwrap().stroke.miter_limit, 4.0);
+        for miter_limit in [0.5, -1.0, f64::NAN, f64::INFINITY] {
+            let err = stroke_params(1, miter_limit).unwrap_err();
+            assert!(err.to_string().starts_with("invalid miter_limit:"), "{err}");
+        }
+    }
+
+    fn path_elements(x: &[f64], y: &[f64], close: bool) -> Vec<vello::kurbo::PathEl> {
+        xy_to_path(x.to_vec(), y.to_vec(), close)
+            .unwrap()
+            .elements()
+            .to_vec()
+    }
+
+    fn is_finite(el: &vello::kurbo::PathEl) -> bool {
+        use vello::kurbo::PathEl;
+
+        match *el {
+            PathEl::MoveTo(p) | PathEl::LineTo(p) => p.is_finite(),
+            PathEl::ClosePath => true,
+            _ => unreachable!(),
+        }
+    }
+
+    fn count_subpaths(elements: &[vello::kurbo::PathEl]) -> usize {
+        elements
+            .iter()
+            .filter(|el| matches!(el, vello::kurbo::PathEl::MoveTo(_)))
+            .count()
+    }
+
+    #[test]
+    fn test_xy_to_path_length_mismatch() {
+        let err = xy_to_path(vec![0.0, 1.0], vec![0.0], false).unwrap_err();
+        assert!(err.to_string().starts_with("invalid y:"), "{err}");
+    }
+
+    #[test]
+    fn test_xy_to_path_nan() {
+        use vello::kurbo::{PathEl, Point};
+
+        let nan = f64::NAN;
+        let p1 = Point::new(1.0, 1.0);
+        let p2 = Point::new(2.0, 2.0);
+        let p3 = Point::new(3.0, 3.0);
+
+        // Leading, trailing, and doubled NaNs (in either coordinate)
+        let cases: [(&[f64], &[f64], Vec<PathEl>); 4] = [
+            (
+                &[nan, 1.0, 2.0],
+                &[0.0, 1.0, 2.0],
+                vec![PathEl::MoveTo(p1), PathEl::LineTo(p2)],
+            ),
+            (
+                &[1.0, 2.0, 3.0],
+                &[1.0, 2.0, nan],
+                vec![PathEl::MoveTo(p1), PathEl::LineTo(p2)],
+            ),
+            (
+                &[1.0, nan, 0.0, 2.0, 3.0],
+                &[1.0, 0.0, nan, 2.0, 3.0],
+                vec![PathEl::MoveTo(p1), PathEl::MoveTo(p2), PathEl::LineTo(p3)],
+            ),
+            (&[nan, nan], &[nan, nan], vec![]),
+        ];
+        for (x, y, expected) in cases {
+            let elements = path_elements(x, y, false);
+            assert!(elements.iter().all(is_finite), "{elements:?}");
+            assert_eq!(elements, expected);
+        }
+    }
+
+    #[test]
+    fn test_xy_to_path_polygon_with_nan() {
+        use vello::kurbo::{PathEl, Point};
+
+        let x = [0.0, 1.0, f64::NAN, 2.0, 3.0, 4.0];
+        let y = [0.0, 1.0, f64::NAN, 2.0, 3.0, 4.0];
+        assert_eq!(
+            path_elements(&x, &y, true),
+            [
+                PathEl::MoveTo(Point::new(0.0, 0.0)),
+                PathEl::LineTo(Point::new(1.0, 1.0)),
+                PathEl::ClosePath,
+                PathEl::MoveTo(Point::new(2.0, 2.0)),
+                PathEl::LineTo(Point::new(3.0, 3.0)),
+                PathEl::LineTo(Point::new(4.0, 4.0)),
+                PathEl::ClosePath,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_xy_to_path_isolated_point() {
+        use vello::kurbo::{PathEl, Point};
+
+        let x = [f64::NAN, 1.0, f64::NAN];
+        let y = [0.0, 1.0, 0.0];
+        assert_eq!(
+            path_elements(&x, &y, false),
+            [PathEl::MoveTo(Point::new(1.0, 1.0))]
+        );
+    }
+
+    #[test]
+    fn test_xy_to_path_subpath_count() {
+        let nan = f64::NAN;
+        // The number of the runs of finite points
+        let cases: [(&[f64], usize); 6] = [
+            (&[], 0),
+            (&[1.0, 2.0, 3.0], 1),
+            (&[nan, 1.0, nan], 1),
+            (&[1.0, nan, 2.0], 2),
+            (&[1.0, 2.0, nan, nan, 3.0, nan, 4.0, 5.0], 3),
+            (&[nan, nan, nan], 0),
+        ];
+        for (x, runs) in cases {
+            for close in [false, true] {
+                let elements = path_elements(x, x, close);
+                assert_eq!(count_subpaths(&elements), runs, "{x:?}");
+            }
+        }
+    }
+
+    // xorshift64*, so that the failing case is reproducible without an
+    // additional dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545f4914f6cdd1d)
+        }
+
+        fn coordinate(&mut self) -> f64 {
+            match self.next() % 8 {
+                0 => f64::NAN,
+                1 => f64::INFINITY,
+                2 => f64::NEG_INFINITY,
+                _ => (self.next() % 1000) as f64 / 10.0,
+            }
+        }
+    }
+
+    #[test]
+    fn test_xy_to_path_random() {
+        use vello::kurbo::PathEl;
+
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..1000 {
+            let len = (rng.next() % 30) as usize;
+            let x = (0..len).map(|_| rng.coordinate()).collect::<Vec<_>>();
+            let y = (0..len).map(|_| rng.coordinate()).collect::<Vec<_>>();
+
+            let finite = x
+                .iter()
+                .zip(&y)
+                .map(|(x, y)| x.is_finite() && y.is_finite())
+                .collect::<Vec<_>>();
+            let runs = finite
+                .iter()
+                .enumerate()
+                .filter(|&(i, &f)| f && (i == 0 || !finite[i - 1]))
+                .count();
+            let points = finite.iter().filter(|&&f| f).count();
+
+            for close in [false, true] {
+                let elements = path_elements(&x, &y, close);
+                let case = format!("x: {x:?}, y: {y:?}, close: {close}");
+
+                assert!(elements.iter().all(is_finite), "{case}");
+                assert_eq!(count_subpaths(&elements), runs, "{case}");
+                let num_points = elements
+                    .iter()
+                    .filter(|el| !matches!(el, PathEl::ClosePath))
+                    .count();
+                assert_eq!(num_points, points, "{case}");
+
+                // Each subpath ends with ClosePath if and only if close is true
+                let num_closes = elements
+                    .iter()
+                    .filter(|el| matches!(el, PathEl::ClosePath))
+                    .count();
+                assert_eq!(num_closes, if close { runs } else { 0 }, "{case}");
+                if close {
+                    for (i, el) in elements.iter().enumerate() {
+                        if matches!(el, PathEl::MoveTo(_)) && i > 0 {
+                            assert_eq!(elements[i - 1], PathEl::ClosePath, "{case}");
+                        }
+                    }
+                    if let Some(last) = elements.last() {
+                        assert_eq!(*last, PathEl::ClosePath, "{case}");
+                    }
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,86 @@
+// Validation of the values in the requests. An invalid value is reported to
+// the client as InvalidArgument with the path to the field (e.g.
+// "stroke_params.join") instead of panicking on the server, so a buggy client
+// can't kill the device.
+
+use tonic::Status;
+
+#[derive(Debug)]
+pub(crate) struct ValidationError {
+    field: String,
+    message: String,
+}
+
+impl ValidationError {
+    pub(crate) fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Prepends the name of the enclosing field to the field path.
+    pub(crate) fn in_field(mut self, parent: &str) -> Self {
+        self.field = format!("{parent}.{}", self.field);
+        self
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid {}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<ValidationError> for Status {
+    fn from(value: ValidationError) -> Self {
+        Status::invalid_argument(value.to_string())
+    }
+}
+
+pub(crate) fn required<T>(field: &str, value: Option<T>) -> Result<T, ValidationError> {
+    value.ok_or_else(|| ValidationError::new(field, "must be specified"))
+}
+
+pub(crate) fn finite<T: Into<f64> + Copy>(field: &str, value: T) -> Result<T, ValidationError> {
+    if value.into().is_finite() {
+        Ok(value)
+    } else {
+        Err(ValidationError::new(
+            field,
+            format!("must be finite, but got {}", value.into()),
+        ))
+    }
+}
+
+pub(crate) fn at_most<T: PartialOrd + std::fmt::Display>(
+    field: &str,
+    value: T,
+    max: T,
+) -> Result<T, ValidationError> {
+    if value <= max {
+        Ok(value)
+    } else {
+        Err(ValidationError::new(
+            field,
+            format!("must be at most {max}, but got {value}"),
+        ))
+    }
+}
+
+pub(crate) fn non_negative<T: Into<f64> + Copy>(
+    field: &str,
+    value: T,
+) -> Result<T, ValidationError> {
+    finite(field, value)?;
+    if value.into() >= 0.0 {
+        Ok(value)
+    } else {
+        Err(ValidationError::new(
+            field,
+            format!("must not be negative, but got {}", value.into()),
+        ))
+    }
+}